@@ -3,9 +3,11 @@ use std::time::{SystemTime};
 use chrono::{DateTime, Local};
 use tracing_subscriber::fmt::format::FmtSpan;
 use kubera::accounts::{Account, AccountSystem};
-use kubera::orders::{ExecutionType, Order, OrderStatus, OrderSystem, PriceType, TradeType};
-use kubera::assets::{AssetSystem, Currency, CryptoCurrency};
-use kubera::matcher::{MatcherSystem};
+use kubera::amount::Amount;
+use kubera::orders::{ExecutionType, Order, OrderSystem, PriceType, SlippageLimit, TradeType};
+use kubera::fees::FeeSchedule;
+use kubera::assets::{Asset, AssetKind, AssetStatus, AssetSystem, Market, MarketStatus, TradingRules};
+use kubera::matcher::{MatcherEvent, MatcherSystem};
 use kubera::storage::StorageSystem;
 fn main() {
 
@@ -24,25 +26,30 @@ fn main() {
     let _ = std::fs::remove_dir_all("database");
     let storage_system = Arc::new(StorageSystem::new());
     let mut assets_system =  AssetSystem::new(storage_system.clone());
-    if assets_system.get_currencies().len() == 0 {
-        let _ = assets_system.create_currency(Currency { id: 0, symbol: "USD".to_string() });
+    if assets_system.get_assets().is_empty() {
+        let _ = assets_system.create_asset(Asset { id: 0, symbol: "USD".to_string(), name: "US Dollar".to_string(), kind: AssetKind::Fiat, precision: 2, status: AssetStatus::Active });
+        let _ = assets_system.create_asset(Asset { id: 0, symbol: "BTC".to_string(), name: "Bitcoin".to_string(), kind: AssetKind::Crypto, precision: 8, status: AssetStatus::Active });
     }
-    if assets_system.get_crypto_currencies().len() == 0 {
-        let _ = assets_system.create_crypto_currency(CryptoCurrency { id: 0, symbol: "BTC".to_string() });
+    if assets_system.get_markets().is_empty() {
+        let trading_rules = TradingRules { tick_size: Some(Amount::new(1, 2)), lot_size: Some(Amount::new(1, 3)), min_quantity: Some(Amount::new(1, 3)), max_quantity: None, min_notional: Some(Amount::from(10)) };
+        let _ = assets_system.create_market(Market { id: 0, base_asset_id: assets_system.get_asset_by_symbol("BTC").unwrap().id, quote_asset_id: assets_system.get_asset_by_symbol("USD").unwrap().id, status: MarketStatus::Trading, trading_rules });
     }
 
     let assets_system = Arc::new(assets_system);
     let mut accounts_system = AccountSystem::new(storage_system.clone(), assets_system.clone());
 
-    let currency_id = assets_system.get_currencies()[0].id;
-    let crypto_currency_id = assets_system.get_crypto_currencies()[0].id;
+    let currency_id = assets_system.get_asset_by_symbol("USD").unwrap().id;
+    let crypto_currency_id = assets_system.get_asset_by_symbol("BTC").unwrap().id;
+    let market_id = assets_system.get_markets()[0].id;
 
 
-    if storage_system.load_accounts().len() == 0 {
+    if storage_system.load_accounts().is_empty() {
         let account1_id = accounts_system.create_account(Account { id: 0, name: "Alice".to_string(), timestamp: SystemTime::now() });
         let account2_id = accounts_system.create_account(Account { id: 0, name: "Bob".to_string(), timestamp: SystemTime::now() });
-        accounts_system.add_currency_to_account(account1_id, currency_id, 100000.0);
-        accounts_system.add_crypto_currency_to_account(account2_id, crypto_currency_id, 1.0);
+        accounts_system.add_asset_to_account(account1_id, currency_id, Amount::from(100000));
+        accounts_system.add_asset_to_account(account2_id, crypto_currency_id, Amount::from(1));
+        let fee_account_id = accounts_system.create_account(Account { id: 0, name: "Exchange".to_string(), timestamp: SystemTime::now() });
+        accounts_system.fee_system.set_fee_schedule(&FeeSchedule { market_id, maker_rate: Amount::new(1, 3), taker_rate: Amount::new(2, 3), fee_account_id, tiers: vec![] });
     }
     let accounts = storage_system.load_accounts();
     let account1_id = accounts[0].id;
@@ -53,19 +60,25 @@ fn main() {
     let core_ids = core_affinity::get_core_ids().unwrap();
     let core_id = core_ids[0];
 
-    let matcher_system = MatcherSystem::start(crypto_currency_id, currency_id, core_id);
-    let order1 = order_system.create_order(Order { id: 0, account_id: account1_id, trade_type: TradeType::Buy, price_type: PriceType::Market, execution_type: ExecutionType::Full, crypto_currency_id: crypto_currency_id, currency_id, quantity: 0.5,  status: OrderStatus::Open, timestamp: SystemTime::now()});
-    let order2 = order_system.create_order(Order { id: 0, account_id: account2_id, trade_type: TradeType::Sell, price_type: PriceType::Limit(50000.00), execution_type: ExecutionType::Partial, crypto_currency_id: crypto_currency_id, currency_id, quantity: 1.0,  status: OrderStatus::Open, timestamp: SystemTime::now()});
-    let _ = matcher_system.add_order(order1);
-    let _ = matcher_system.add_order(order2);
+    let base_precision = assets_system.get_asset(crypto_currency_id).unwrap().precision;
+    let quote_precision = assets_system.get_asset(currency_id).unwrap().precision;
+    let matcher_system = MatcherSystem::start(market_id, base_precision, quote_precision, core_id);
+    let order1 = order_system.create_order(Order { account_id: account1_id, trade_type: TradeType::Buy, price_type: PriceType::Market, execution_type: ExecutionType::Full, market_id, quantity: Amount::new(5, 1), slippage: Some(SlippageLimit::WorstPrice(Amount::from(51000))), ..Default::default() }, &mut accounts_system).unwrap();
+    let order2 = order_system.create_order(Order { account_id: account2_id, trade_type: TradeType::Sell, price_type: PriceType::Limit(Amount::from(50000)), execution_type: ExecutionType::Partial, market_id, quantity: Amount::from(1), ..Default::default() }, &mut accounts_system).unwrap();
+    // The sell rests in the book and the market buy takes it
+    matcher_system.add_order(order2);
+    matcher_system.add_order(order1);
     print_accounts(storage_system.clone());
     loop {
-        while let Some(order_match) = matcher_system.get_order_match() {
-           tracing::info!("OrderMatch: Buy Order Id: {} Sell Order Id: {} Quantity: {} Price: {}", order_match.buy_order_id, order_match.sell_order_id, order_match.quantity, order_match.price);
-           order_system.create_order_history(&order_match, &mut accounts_system);
+        while let Some(event) = matcher_system.get_event() {
+           if let MatcherEvent::Match(order_match) = &event {
+               tracing::info!("OrderMatch: Buy Order Id: {} Sell Order Id: {} Quantity: {} Price: {}", order_match.buy_order_id, order_match.sell_order_id, order_match.quantity, order_match.price);
+           }
+           order_system.process_event(&event, &mut accounts_system);
+           accounts_system.ledger_system.check_trial_balance().unwrap();
            print_accounts(storage_system.clone());
-           if storage_system.get_account_currency(account1_id, currency_id).unwrap().balance > 0.0 {
-               let order = order_system.create_order(Order { id: 0, account_id: account1_id, trade_type: TradeType::Buy, price_type: PriceType::Market, execution_type: ExecutionType::Full, crypto_currency_id: crypto_currency_id, currency_id, quantity: 0.5,  status: OrderStatus::Open, timestamp: SystemTime::now()});
+           // Keeps buying as long as the available balance covers the worst price
+           if let Ok(order) = order_system.create_order(Order { account_id: account1_id, trade_type: TradeType::Buy, price_type: PriceType::Market, execution_type: ExecutionType::Full, market_id, quantity: Amount::new(5, 1), slippage: Some(SlippageLimit::WorstPrice(Amount::from(51000))), ..Default::default() }, &mut accounts_system) {
                matcher_system.add_order(order);
            }
         }
@@ -82,27 +95,17 @@ fn print_accounts(storage_system: Arc<StorageSystem>) {
             "AccountId: {} Name: {} Timestamp: {}",account.id,  account.name, datetime.format("%Y-%m-%d %H:%M:%S").to_string()
         };
 
-        for account_currency in storage_system.get_account_currency_by_account_id(account.id) {
-            tracing::info! {
-                "CurrencyId: {} Symbol: {} Balance: {:.2}", account_currency.id, storage_system.get_currency(account_currency.currency_id).unwrap().symbol, account_currency.balance
-            };
-
-            for account_currency_history in storage_system.get_currency_history_by_account_id_account_currency_id(account.id, account_currency.id) {
-                let datetime: DateTime<Local> = account_currency_history.timestamp.into();
-                tracing::info! {
-                    "CurrencyHistoryId: {} Balance: {:.2} Timestamp: {}", account_currency_history.id,  account_currency_history.balance, datetime.format("%Y-%m-%d %H:%M:%S").to_string()
-                };
-            }
-        }
-        for account_crypto_currency in storage_system.get_account_crypto_currencies_by_account_id(account.id) {
+        for account_asset in storage_system.get_account_assets_by_account_id(account.id) {
+            let asset = storage_system.get_asset(account_asset.asset_id).unwrap();
+            let precision = asset.precision as usize;
             tracing::info! {
-                "CryptoCurrencyId: {} {} Amount: {}", account_crypto_currency.id, storage_system.get_crypto_currency(account_crypto_currency.crypto_currency_id).unwrap().symbol, account_crypto_currency.quantity
+                "AccountAssetId: {} Symbol: {} Balance: {:.precision$} Held: {:.precision$}", account_asset.id, asset.symbol, account_asset.balance, account_asset.held
             };
 
-            for account_crypto_currency_history in storage_system.get_crypto_currency_history_by_account_id_crypto_currency_id(account.id, account_crypto_currency.crypto_currency_id) {
-                let datetime: DateTime<Local> = account_crypto_currency_history.timestamp.into();
+            for account_asset_history in storage_system.get_account_asset_histories_by_account_id_asset_id(account.id, account_asset.asset_id) {
+                let datetime: DateTime<Local> = account_asset_history.timestamp.into();
                 tracing::info! {
-                    "CryptoCurrencyHistoryId: {} Quantity: {} Timestamp: {}", account_crypto_currency_history.id,  account_crypto_currency_history.quantity, datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+                    "AccountAssetHistoryId: {} Balance: {:.precision$} Held: {:.precision$} Timestamp: {}", account_asset_history.id,  account_asset_history.balance, account_asset_history.held, datetime.format("%Y-%m-%d %H:%M:%S").to_string()
                 };
             }
         }
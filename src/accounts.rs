@@ -2,8 +2,12 @@ use std::sync::Arc;
 use std::time::{SystemTime};
 use bincode::{Decode, Encode};
 use tracing::{Level, span};
+use crate::amount::Amount;
 use crate::assets::AssetSystem;
-use crate::storage::StorageSystem;
+use crate::fees::FeeSystem;
+use crate::funding::{Deposit, FundingAdapter, FundingError, FundingStatus, TransactionStatus, Withdrawal};
+use crate::ledger::{EntryType, JournalReference, LedgerAccount, LedgerSystem};
+use crate::storage::{Settlement, StorageSystem};
 
 #[derive(Encode, Decode,  Debug)]
 pub struct Account {
@@ -13,15 +17,48 @@ pub struct Account {
 }
 
 
+/// Balance of one asset in an account.
 #[derive(Encode, Decode, Debug)]
-pub struct AccountCurrency {
+pub struct AccountAsset {
+    pub id: u64,
+    pub account_id: u64,
+    pub asset_id: u64,
+    /// Everything the account owns, including `held`.
+    pub balance: Amount,
+    /// Part of the balance reserved for open orders.
+    pub held: Amount,
+}
+
+impl AccountAsset {
+    /// Part of the balance that can be used for new orders.
+    pub fn available(&self) -> Amount {
+        self.balance - self.held
+    }
+}
+
+/// Balance and held amount of an account asset after every change.
+#[derive(Encode, Decode, Debug)]
+pub struct AccountAssetHistory {
+    pub id: u64,
+    pub account_id: u64,
+    pub asset_id: u64,
+    pub balance: Amount,
+    pub held: Amount,
+    pub timestamp: SystemTime,
+}
+
+// Account balances as stored before `Asset`, only read to migrate old databases.
+
+#[derive(Encode, Decode, Debug)]
+pub(crate) struct AccountCurrency {
     pub id: u64,
     pub account_id: u64,
     pub currency_id: u64,
     pub balance: f64,
 }
+
 #[derive(Encode, Decode, Debug)]
-pub struct AccountCurrencyHistory {
+pub(crate) struct AccountCurrencyHistory {
     pub id: u64,
     pub account_id: u64,
     pub account_currency_id: u64,
@@ -31,7 +68,7 @@ pub struct AccountCurrencyHistory {
 }
 
 #[derive(Encode, Decode, Debug)]
-pub struct AccountCryptoCurrency {
+pub(crate) struct AccountCryptoCurrency {
     pub id: u64,
     pub account_id: u64,
     pub crypto_currency_id: u64,
@@ -39,7 +76,7 @@ pub struct AccountCryptoCurrency {
 }
 
 #[derive(Encode, Decode, Debug)]
-pub struct AccountCryptoCurrencyHistory {
+pub(crate) struct AccountCryptoCurrencyHistory {
     pub id: u64,
     pub account_id: u64,
     pub crypto_currency_id: u64,
@@ -50,12 +87,16 @@ pub struct AccountCryptoCurrencyHistory {
 
 pub struct AccountSystem {
     pub account_last_id: u64,
-    pub account_currencies_last_id: u64,
-    pub account_currency_histories_last_id: u64,
-    pub account_crypto_currencies_last_id: u64,
-    pub account_crypto_currency_histories_last_id: u64,
+    pub account_assets_last_id: u64,
+    pub account_asset_histories_last_id: u64,
+    pub deposit_last_id: u64,
+    pub withdrawal_last_id: u64,
     pub storage_system: Arc<StorageSystem>,
     pub asset_system: Arc<AssetSystem>,
+    /// Journal every balance change is posted to.
+    pub ledger_system: LedgerSystem,
+    /// Fee schedules and the fees charged to accounts.
+    pub fee_system: FeeSystem,
 }
 
 
@@ -68,41 +109,43 @@ impl AccountSystem {
                 account_last_id = account.id;
             }
         }
-        let mut account_currencies_last_id = 0;
-        match storage_system.get_last_account_currency() {
+        let mut account_assets_last_id = 0;
+        match storage_system.get_last_account_asset() {
             None => {}
-            Some(account_currency) => {
-                account_currencies_last_id = account_currency.id;
+            Some(account_asset) => {
+                account_assets_last_id = account_asset.id;
             }
         }
-        let mut account_currency_histories_last_id = 0;
-        match storage_system.get_last_account_currency_history() {
+        let mut account_asset_histories_last_id = 0;
+        match storage_system.get_last_account_asset_history() {
             None => {}
-            Some(account_currency_history) => {
-                account_currency_histories_last_id = account_currency_history.id;
+            Some(account_asset_history) => {
+                account_asset_histories_last_id = account_asset_history.id;
             }
         }
-        let mut account_crypto_currencies_last_id = 0;
-        match storage_system.get_last_account_crypto_currency() {
+        let mut deposit_last_id = 0;
+        match storage_system.get_last_deposit() {
             None => {}
-            Some(account_crypto_currency) => {
-                account_crypto_currencies_last_id = account_crypto_currency.id;
+            Some(deposit) => {
+                deposit_last_id = deposit.id;
             }
         }
-        let mut account_crypto_currency_histories_last_id = 0;
-        match storage_system.get_last_account_crypto_currency_history() {
+        let mut withdrawal_last_id = 0;
+        match storage_system.get_last_withdrawal() {
             None => {}
-            Some(account_crypto_currency_history) => {
-                account_crypto_currency_histories_last_id = account_crypto_currency_history.id;
+            Some(withdrawal) => {
+                withdrawal_last_id = withdrawal.id;
             }
         }
 
         AccountSystem {
             account_last_id,
-            account_currencies_last_id,
-            account_currency_histories_last_id,
-            account_crypto_currencies_last_id,
-            account_crypto_currency_histories_last_id,
+            account_assets_last_id,
+            account_asset_histories_last_id,
+            deposit_last_id,
+            withdrawal_last_id,
+            ledger_system: LedgerSystem::new(storage_system.clone()),
+            fee_system: FeeSystem::new(storage_system.clone()),
             storage_system,
             asset_system,
         }
@@ -118,87 +161,310 @@ impl AccountSystem {
         let _ = add_account.enter();
         self.storage_system.add_account(&account);
         drop(add_account);
-        let currencies = self.asset_system.get_currencies();
-        for currency in currencies {
-            self.create_account_currency(self.account_last_id, currency.id);
+        let assets = self.asset_system.get_assets();
+        for asset in assets {
+            self.create_account_asset(self.account_last_id, asset.id);
         }
         self.account_last_id
     }
 
-
-
-    pub fn create_account_currency(&mut self, account_id: u64, currency_id: u64) -> u64 {
-        self.account_currencies_last_id += 1;
-        let account_currency = AccountCurrency {
-            id: self.account_currencies_last_id,
+    pub fn create_account_asset(&mut self, account_id: u64, asset_id: u64) -> u64 {
+        self.account_assets_last_id += 1;
+        let account_asset = AccountAsset {
+            id: self.account_assets_last_id,
             account_id,
-            currency_id,
-            balance: 0.0,
+            asset_id,
+            balance: Amount::ZERO,
+            held: Amount::ZERO,
+        };
+        self.storage_system.add_account_asset(&account_asset);
+        self.account_assets_last_id
+    }
+
+    /// Credits (or debits with a negative amount) an asset from outside the exchange right away,
+    /// posted as a deposit (or withdrawal) against `LedgerAccount::External`.
+    /// The amount can't have more decimal places than the asset.
+    /// Funds that have to be confirmed first go through `create_deposit` and `request_withdrawal`.
+    pub fn add_asset_to_account(&mut self, account_id: u64, asset_id: u64, amount: Amount) {
+        let entry_type = if amount.is_negative() {
+            EntryType::Withdrawal
+        } else {
+            EntryType::Deposit
         };
-        self.storage_system.add_account_currency(&account_currency);
-        self.account_currencies_last_id
+        let mut settlement = Settlement::default();
+        self.stage_journal_entry(&mut settlement, entry_type, JournalReference::None, &[
+            (LedgerAccount::Account(account_id), asset_id, amount),
+            (LedgerAccount::External, asset_id, -amount),
+        ]);
+        self.storage_system.settle(&settlement);
     }
 
+    /// Moves an asset from one account to another.
+    pub fn transfer_asset(&mut self, from_account_id: u64, to_account_id: u64, asset_id: u64, amount: Amount) {
+        let mut settlement = Settlement::default();
+        self.stage_journal_entry(&mut settlement, EntryType::Transfer, JournalReference::None, &[
+            (LedgerAccount::Account(from_account_id), asset_id, -amount),
+            (LedgerAccount::Account(to_account_id), asset_id, amount),
+        ]);
+        self.storage_system.settle(&settlement);
+    }
+
+    /// Balance of the asset not held for open orders, zero if the account never had the asset.
+    pub fn get_available_balance(&self, account_id: u64, asset_id: u64) -> Amount {
+        match self.storage_system.get_account_asset(account_id, asset_id) {
+            None => Amount::ZERO,
+            Some(account_asset) => account_asset.available(),
+        }
+    }
 
-    pub fn add_currency_to_account(&mut self, account_id: u64, currency_id: u64, balance: f64) {
-        let mut account_currency = self.storage_system.get_account_currency(account_id, currency_id).unwrap();
-        account_currency.balance += balance;
-        let balance = account_currency.balance;
-        self.storage_system.update_account_currency(&account_currency);
-        self.add_account_currency_history(account_id, account_currency.id, currency_id, balance);
+    /// Available balance as it is once the settlement is stored.
+    pub fn get_staged_available_balance(&self, settlement: &Settlement, account_id: u64, asset_id: u64) -> Amount {
+        match settlement.account_assets.iter().find(|account_asset| account_asset.account_id == account_id && account_asset.asset_id == asset_id) {
+            Some(account_asset) => account_asset.available(),
+            None => self.get_available_balance(account_id, asset_id),
+        }
     }
 
-    pub fn add_account_currency_history(&mut self, account_id: u64, account_currency_id: u64,  currency_id: u64, balance: f64) {
-        self.account_currency_histories_last_id += 1;
-        let account_currency_history = AccountCurrencyHistory {
-            id: self.account_currency_histories_last_id,
+    /// Records a deposit seen by the adapter, it is credited by `complete_deposit` once it has enough confirmations.
+    /// A transaction is only recorded once per asset.
+    pub fn create_deposit(&mut self, account_id: u64, asset_id: u64, amount: Amount, reference: String, adapter: &dyn FundingAdapter) -> Result<Deposit, FundingError> {
+        self.check_funding_amount(asset_id, amount)?;
+        if let Some(deposit) = self.storage_system.get_deposit_by_reference(asset_id, &reference) {
+            return Err(FundingError::DuplicateReference { reference, deposit_id: deposit.id });
+        }
+        self.deposit_last_id += 1;
+        let deposit = Deposit {
+            id: self.deposit_last_id,
             account_id,
-            account_currency_id,
-            currency_id,
-            balance,
+            asset_id,
+            amount,
+            reference,
+            confirmations: 0,
+            required_confirmations: adapter.required_confirmations(asset_id),
+            status: FundingStatus::Pending,
             timestamp: SystemTime::now(),
         };
-        self.storage_system.add_account_currency_history(&account_currency_history);
+        self.storage_system.settle(&Settlement { deposits: vec![deposit.clone()], ..Default::default() });
+        Ok(deposit)
+    }
+
+    /// Asks the adapter for the confirmations of a pending deposit, it is `Confirmed` once it has enough of them
+    /// and `Rejected` if the transaction failed. It stays pending while the adapter doesn't know the transaction.
+    pub fn update_deposit(&mut self, deposit_id: u64, adapter: &dyn FundingAdapter) -> Result<Deposit, FundingError> {
+        let mut deposit = self.get_deposit_with_status(deposit_id, &[FundingStatus::Pending])?;
+        match adapter.get_confirmations(&deposit.reference) {
+            TransactionStatus::Unknown => return Ok(deposit),
+            TransactionStatus::Failed => deposit.status = FundingStatus::Rejected,
+            TransactionStatus::Confirmations(confirmations) => {
+                deposit.confirmations = confirmations;
+                if confirmations >= deposit.required_confirmations {
+                    deposit.status = FundingStatus::Confirmed;
+                }
+            }
+        }
+        self.storage_system.settle(&Settlement { deposits: vec![deposit.clone()], ..Default::default() });
+        Ok(deposit)
+    }
+
+    /// Credits a confirmed deposit to the account.
+    pub fn complete_deposit(&mut self, deposit_id: u64) -> Result<Deposit, FundingError> {
+        let mut deposit = self.get_deposit_with_status(deposit_id, &[FundingStatus::Confirmed])?;
+        deposit.status = FundingStatus::Completed;
+        let mut settlement = Settlement::default();
+        self.stage_journal_entry(&mut settlement, EntryType::Deposit, JournalReference::Deposit { deposit_id }, &[
+            (LedgerAccount::Account(deposit.account_id), deposit.asset_id, deposit.amount),
+            (LedgerAccount::External, deposit.asset_id, -deposit.amount),
+        ]);
+        settlement.deposits.push(deposit.clone());
+        self.storage_system.settle(&settlement);
+        Ok(deposit)
+    }
+
+    /// Rejects a deposit that wasn't credited yet.
+    pub fn reject_deposit(&mut self, deposit_id: u64) -> Result<Deposit, FundingError> {
+        let mut deposit = self.get_deposit_with_status(deposit_id, &[FundingStatus::Pending, FundingStatus::Confirmed])?;
+        deposit.status = FundingStatus::Rejected;
+        self.storage_system.settle(&Settlement { deposits: vec![deposit.clone()], ..Default::default() });
+        Ok(deposit)
+    }
+
+    pub fn get_deposit(&self, deposit_id: u64) -> Option<Deposit> {
+        self.storage_system.get_deposit(deposit_id)
+    }
+
+    pub fn get_deposits(&self, account_id: u64) -> Vec<Deposit> {
+        self.storage_system.get_deposits_by_account_id(account_id)
     }
 
-    pub fn create_account_crypto_currency(&mut self, account_id: u64, crypto_currency_id: u64) -> u64 {
-        self.account_crypto_currencies_last_id += 1;
-        let account_crypto_currency = AccountCryptoCurrency {
-            id: self.account_crypto_currencies_last_id,
+    /// Holds the amount of a new withdrawal until it is completed or rejected.
+    pub fn request_withdrawal(&mut self, account_id: u64, asset_id: u64, amount: Amount, destination: String) -> Result<Withdrawal, FundingError> {
+        self.check_funding_amount(asset_id, amount)?;
+        let available = self.get_available_balance(account_id, asset_id);
+        if amount > available {
+            return Err(FundingError::InsufficientFunds { asset_id, required: amount, available });
+        }
+        self.withdrawal_last_id += 1;
+        let withdrawal = Withdrawal {
+            id: self.withdrawal_last_id,
             account_id,
-            crypto_currency_id,
-            quantity: 0.0,
+            asset_id,
+            amount,
+            destination,
+            reference: None,
+            confirmations: 0,
+            required_confirmations: 0,
+            status: FundingStatus::Pending,
+            timestamp: SystemTime::now(),
         };
-        self.storage_system.add_account_crypto_currency(&account_crypto_currency);
-        self.account_crypto_currencies_last_id
-    }
-    pub fn add_crypto_currency_to_account(&mut self, account_id: u64, crypto_curreny_id: u64, quantity: f64) {
-        let account_crypto_currency_opt = self.storage_system.get_account_crypto_currency(account_id, crypto_curreny_id);
-        let mut account_crypto_currency = match account_crypto_currency_opt {
-            None => {
-                let account_crypto_currency_id = self.create_account_crypto_currency(account_id, crypto_curreny_id);
-                self.storage_system.get_account_crypto_currency_by_id(account_crypto_currency_id).unwrap()
+        let mut settlement = Settlement::default();
+        self.stage_hold_change(&mut settlement, account_id, asset_id, amount);
+        settlement.withdrawals.push(withdrawal.clone());
+        self.storage_system.settle(&settlement);
+        Ok(withdrawal)
+    }
+
+    /// Approves a pending withdrawal and sends it through the adapter, it stays pending if sending fails.
+    pub fn approve_withdrawal(&mut self, withdrawal_id: u64, adapter: &mut dyn FundingAdapter) -> Result<Withdrawal, FundingError> {
+        let mut withdrawal = self.get_withdrawal_with_status(withdrawal_id, &[FundingStatus::Pending])?;
+        let reference = adapter.send_withdrawal(&withdrawal).map_err(FundingError::Adapter)?;
+        withdrawal.reference = Some(reference);
+        withdrawal.required_confirmations = adapter.required_confirmations(withdrawal.asset_id);
+        withdrawal.status = FundingStatus::Confirmed;
+        self.storage_system.settle(&Settlement { withdrawals: vec![withdrawal.clone()], ..Default::default() });
+        Ok(withdrawal)
+    }
+
+    /// Releases the funds of a withdrawal that wasn't approved.
+    pub fn reject_withdrawal(&mut self, withdrawal_id: u64) -> Result<Withdrawal, FundingError> {
+        let withdrawal = self.get_withdrawal_with_status(withdrawal_id, &[FundingStatus::Pending])?;
+        Ok(self.close_withdrawal(withdrawal, FundingStatus::Rejected))
+    }
+
+    /// Asks the adapter for the confirmations of a sent withdrawal. Once it has enough of them the funds
+    /// leave the account and it is `Completed`, if the transaction failed the funds are released and it is `Rejected`.
+    /// The funds stay held while the adapter doesn't know the transaction, as it can still confirm.
+    pub fn update_withdrawal(&mut self, withdrawal_id: u64, adapter: &dyn FundingAdapter) -> Result<Withdrawal, FundingError> {
+        let mut withdrawal = self.get_withdrawal_with_status(withdrawal_id, &[FundingStatus::Confirmed])?;
+        let reference = withdrawal.reference.clone().unwrap_or_default();
+        match adapter.get_confirmations(&reference) {
+            TransactionStatus::Unknown => Ok(withdrawal),
+            TransactionStatus::Failed => Ok(self.close_withdrawal(withdrawal, FundingStatus::Rejected)),
+            TransactionStatus::Confirmations(confirmations) if confirmations >= withdrawal.required_confirmations => {
+                withdrawal.confirmations = confirmations;
+                Ok(self.close_withdrawal(withdrawal, FundingStatus::Completed))
             }
-            Some(account_crypto_currency) => {
-                account_crypto_currency
+            TransactionStatus::Confirmations(confirmations) => {
+                withdrawal.confirmations = confirmations;
+                self.storage_system.settle(&Settlement { withdrawals: vec![withdrawal.clone()], ..Default::default() });
+                Ok(withdrawal)
             }
-        };
-        account_crypto_currency.quantity += quantity;
-        let quantity = account_crypto_currency.quantity;
-        self.storage_system.update_account_crypto_currency(account_crypto_currency);
-        self.add_account_crypto_currency_history(account_id, crypto_curreny_id, quantity);
+        }
+    }
+
+    pub fn get_withdrawal(&self, withdrawal_id: u64) -> Option<Withdrawal> {
+        self.storage_system.get_withdrawal(withdrawal_id)
+    }
+
+    pub fn get_withdrawals(&self, account_id: u64) -> Vec<Withdrawal> {
+        self.storage_system.get_withdrawals_by_account_id(account_id)
+    }
+
+    /// Releases the hold of the withdrawal, a completed withdrawal also takes the funds out of the account.
+    fn close_withdrawal(&mut self, mut withdrawal: Withdrawal, status: FundingStatus) -> Withdrawal {
+        withdrawal.status = status;
+        let mut settlement = Settlement::default();
+        self.stage_hold_change(&mut settlement, withdrawal.account_id, withdrawal.asset_id, -withdrawal.amount);
+        if status == FundingStatus::Completed {
+            self.stage_journal_entry(&mut settlement, EntryType::Withdrawal, JournalReference::Withdrawal { withdrawal_id: withdrawal.id }, &[
+                (LedgerAccount::Account(withdrawal.account_id), withdrawal.asset_id, -withdrawal.amount),
+                (LedgerAccount::External, withdrawal.asset_id, withdrawal.amount),
+            ]);
+        }
+        settlement.withdrawals.push(withdrawal.clone());
+        self.storage_system.settle(&settlement);
+        withdrawal
+    }
+
+    fn get_deposit_with_status(&self, deposit_id: u64, statuses: &[FundingStatus]) -> Result<Deposit, FundingError> {
+        let deposit = self.get_deposit(deposit_id).ok_or(FundingError::DepositNotFound { deposit_id })?;
+        if !statuses.contains(&deposit.status) {
+            return Err(FundingError::InvalidStatus { status: deposit.status });
+        }
+        Ok(deposit)
+    }
+
+    fn get_withdrawal_with_status(&self, withdrawal_id: u64, statuses: &[FundingStatus]) -> Result<Withdrawal, FundingError> {
+        let withdrawal = self.get_withdrawal(withdrawal_id).ok_or(FundingError::WithdrawalNotFound { withdrawal_id })?;
+        if !statuses.contains(&withdrawal.status) {
+            return Err(FundingError::InvalidStatus { status: withdrawal.status });
+        }
+        Ok(withdrawal)
+    }
+
+    /// Deposits and withdrawals move a positive amount with no more decimal places than the asset.
+    fn check_funding_amount(&self, asset_id: u64, amount: Amount) -> Result<(), FundingError> {
+        let asset = self.asset_system.get_asset(asset_id).ok_or(FundingError::AssetNotFound { asset_id })?;
+        if !amount.is_positive() || !amount.has_precision(asset.precision as u32) {
+            return Err(FundingError::InvalidAmount { amount, asset_id });
+        }
+        Ok(())
+    }
+
+    /// Fee tier of the account in the market, based on its trading volume over the last 30 days.
+    /// Tiers are recomputed every `FeeSystem::fee_tier_interval`, see `FeeSystem::update_fee_tiers`.
+    pub fn get_fee_tier(&self, account_id: u64, market_id: u64) -> usize {
+        self.fee_system.get_fee_tier(account_id, market_id)
     }
 
-    pub fn add_account_crypto_currency_history(&mut self, account_id: u64, crypto_currency_id: u64, quantity: f64) {
-        self.account_crypto_currency_histories_last_id += 1;
-        let account_crypto_currency_history = AccountCryptoCurrencyHistory {
-            id: self.account_crypto_currency_histories_last_id,
+    /// Posts a balanced journal entry and changes the balances of the accounts in it, see `LedgerSystem::stage_journal_entry`.
+    pub fn stage_journal_entry(&mut self, settlement: &mut Settlement, entry_type: EntryType, reference: JournalReference, postings: &[(LedgerAccount, u64, Amount)]) -> u64 {
+        let journal_entry_id = self.ledger_system.stage_journal_entry(settlement, entry_type, reference, postings);
+        for (ledger_account, asset_id, amount) in postings {
+            if let LedgerAccount::Account(account_id) = ledger_account {
+                self.stage_account_asset_change(settlement, *account_id, *asset_id, *amount, Amount::ZERO);
+            }
+        }
+        journal_entry_id
+    }
+
+    /// Changes the held part of a balance, which doesn't move funds and isn't posted to the ledger.
+    pub fn stage_hold_change(&mut self, settlement: &mut Settlement, account_id: u64, asset_id: u64, held_change: Amount) {
+        self.stage_account_asset_change(settlement, account_id, asset_id, Amount::ZERO, held_change);
+    }
+
+    /// Changes the balance and held amount of an asset as part of the settlement, together with its history row.
+    /// Nothing is stored before `StorageSystem::settle`.
+    fn stage_account_asset_change(&mut self, settlement: &mut Settlement, account_id: u64, asset_id: u64, balance_change: Amount, held_change: Amount) {
+        let precision = self.asset_system.get_asset(asset_id).unwrap().precision;
+        for amount in [balance_change, held_change] {
+            assert!(amount.has_precision(precision as u32), "{amount} has more than {precision} decimal places");
+        }
+        let staged = settlement.account_assets.iter()
+            .position(|account_asset| account_asset.account_id == account_id && account_asset.asset_id == asset_id);
+        let mut account_asset = match staged {
+            Some(index) => settlement.account_assets.remove(index),
+            None => match self.storage_system.get_account_asset(account_id, asset_id) {
+                Some(account_asset) => account_asset,
+                None => {
+                    // Assets created after the account have no balance yet
+                    self.account_assets_last_id += 1;
+                    AccountAsset { id: self.account_assets_last_id, account_id, asset_id, balance: Amount::ZERO, held: Amount::ZERO }
+                }
+            },
+        };
+        account_asset.balance += balance_change;
+        account_asset.held += held_change;
+        assert!(!account_asset.held.is_negative(), "Held {} of asset {asset_id} of account {account_id} is negative", account_asset.held);
+        self.account_asset_histories_last_id += 1;
+        settlement.account_asset_histories.push(AccountAssetHistory {
+            id: self.account_asset_histories_last_id,
             account_id,
-            crypto_currency_id,
-            quantity,
+            asset_id,
+            balance: account_asset.balance,
+            held: account_asset.held,
             timestamp: SystemTime::now(),
-        };
-        self.storage_system.add_account_crypto_currency_history(&account_crypto_currency_history);
+        });
+        settlement.account_assets.push(account_asset);
     }
 
 }
\ No newline at end of file
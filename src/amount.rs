@@ -0,0 +1,255 @@
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+use std::str::FromStr;
+use bincode::{Decode, Encode};
+
+/// Decimal places every `Amount` has, enough for assets with 18 decimals like most tokens.
+pub const DECIMALS: u32 = 18;
+const SCALE: i128 = 10i128.pow(DECIMALS);
+
+/// Fixed-point decimal used for prices, quantities and balances.
+///
+/// Addition and subtraction are exact, products and quotients are truncated to `DECIMALS` places.
+/// Amounts are rounded to the precision of their asset where they are validated and settled,
+/// so balances add up exactly. Overflow panics.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Encode, Decode)]
+pub struct Amount(i128);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const ONE: Amount = Amount(SCALE);
+
+    /// `mantissa * 10^-scale`, e.g. `Amount::new(5, 1)` is 0.5.
+    pub fn new(mantissa: i64, scale: u32) -> Amount {
+        assert!(scale <= DECIMALS);
+        Amount(mantissa as i128 * 10i128.pow(DECIMALS - scale))
+    }
+
+    /// Amount from its raw value in units of `10^-DECIMALS`.
+    pub fn from_units(units: i128) -> Amount {
+        Amount(units)
+    }
+
+    pub fn units(&self) -> i128 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn abs(self) -> Amount {
+        Amount(self.0.abs())
+    }
+
+    /// Rounds to the given number of decimal places, halves away from zero.
+    pub fn round_dp(self, decimals: u32) -> Amount {
+        if decimals >= DECIMALS {
+            return self;
+        }
+        let step = 10i128.pow(DECIMALS - decimals);
+        let remainder = self.0 % step;
+        let truncated = self.0 - remainder;
+        if remainder.abs() * 2 >= step {
+            Amount(truncated + step * self.0.signum())
+        } else {
+            Amount(truncated)
+        }
+    }
+
+    /// Drops the decimal places after `decimals`.
+    pub fn trunc_dp(self, decimals: u32) -> Amount {
+        if decimals >= DECIMALS {
+            return self;
+        }
+        Amount(self.0 - self.0 % 10i128.pow(DECIMALS - decimals))
+    }
+
+    /// Rounds away from zero to the given number of decimal places.
+    pub fn round_up_dp(self, decimals: u32) -> Amount {
+        let truncated = self.trunc_dp(decimals);
+        if truncated == self {
+            return self;
+        }
+        Amount(truncated.0 + 10i128.pow(DECIMALS - decimals) * self.0.signum())
+    }
+
+    /// Whether the amount has no more than `decimals` decimal places.
+    pub fn has_precision(&self, decimals: u32) -> bool {
+        self.trunc_dp(decimals) == *self
+    }
+
+    /// Whether the amount is a whole number of `step`s.
+    pub fn is_multiple_of(&self, step: Amount) -> bool {
+        step.0 != 0 && self.0 % step.0 == 0
+    }
+
+    pub fn checked_add(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_add(rhs.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_sub(rhs.0).map(Amount)
+    }
+
+    pub fn checked_mul(self, rhs: Amount) -> Option<Amount> {
+        // (ai + af) * (bi + bf) term by term, so only the result itself can overflow
+        let (ai, af) = (self.0 / SCALE, self.0 % SCALE);
+        let (bi, bf) = (rhs.0 / SCALE, rhs.0 % SCALE);
+        ai.checked_mul(bi)?.checked_mul(SCALE)?
+            .checked_add(ai.checked_mul(bf)?)?
+            .checked_add(af.checked_mul(bi)?)?
+            .checked_add(af * bf / SCALE)
+            .map(Amount)
+    }
+
+    pub fn checked_div(self, rhs: Amount) -> Option<Amount> {
+        if rhs.0 == 0 {
+            return None;
+        }
+        // Long division, one decimal place at a time
+        let mut quotient = self.0 / rhs.0;
+        let mut remainder = self.0 % rhs.0;
+        for _ in 0..DECIMALS {
+            remainder = remainder.checked_mul(10)?;
+            quotient = quotient.checked_mul(10)?.checked_add(remainder / rhs.0)?;
+            remainder %= rhs.0;
+        }
+        Some(Amount(quotient))
+    }
+}
+
+impl From<i64> for Amount {
+    fn from(value: i64) -> Amount {
+        Amount(value as i128 * SCALE)
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, rhs: Amount) -> Amount {
+        self.checked_add(rhs).expect("amount overflow")
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, rhs: Amount) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, rhs: Amount) -> Amount {
+        self.checked_sub(rhs).expect("amount overflow")
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, rhs: Amount) {
+        *self = *self - rhs;
+    }
+}
+
+impl Mul for Amount {
+    type Output = Amount;
+
+    fn mul(self, rhs: Amount) -> Amount {
+        self.checked_mul(rhs).expect("amount overflow")
+    }
+}
+
+impl Div for Amount {
+    type Output = Amount;
+
+    fn div(self, rhs: Amount) -> Amount {
+        self.checked_div(rhs).expect("amount overflow or division by zero")
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Amount {
+        iter.fold(Amount::ZERO, |acc, x| acc + x)
+    }
+}
+
+/// Prints the amount without trailing zeros, or rounded to exactly the given precision, e.g. `{:.2}`.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let amount = match f.precision() {
+            Some(precision) => self.round_dp(precision as u32),
+            None => *self,
+        };
+        let sign = if amount.0 < 0 { "-" } else { "" };
+        let units = amount.0.unsigned_abs();
+        let integer = units / SCALE as u128;
+        let fraction = format!("{:0width$}", units % SCALE as u128, width = DECIMALS as usize);
+        let fraction = match f.precision() {
+            Some(precision) => &fraction[..precision.min(DECIMALS as usize)],
+            None => fraction.trim_end_matches('0'),
+        };
+        if fraction.is_empty() {
+            write!(f, "{sign}{integer}")
+        } else {
+            write!(f, "{sign}{integer}.{fraction}")
+        }
+    }
+}
+
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Error of parsing an `Amount` from a string like "-1234.5678".
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseAmountError(String);
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid amount \"{}\"", self.0)
+    }
+}
+
+impl std::error::Error for ParseAmountError {}
+
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Amount, ParseAmountError> {
+        let error = || ParseAmountError(s.to_string());
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if integer.is_empty() || !is_digits(integer) || !is_digits(fraction) || fraction.len() > DECIMALS as usize {
+            return Err(error());
+        }
+        let integer: i128 = integer.parse().map_err(|_| error())?;
+        let fraction: i128 = format!("{fraction:0<width$}", width = DECIMALS as usize).parse().map_err(|_| error())?;
+        let units = integer.checked_mul(SCALE).and_then(|units| units.checked_add(fraction)).ok_or_else(error)?;
+        Ok(Amount(if negative { -units } else { units }))
+    }
+}
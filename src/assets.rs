@@ -1,73 +1,223 @@
 use std::sync::Arc;
 use bincode::{Decode, Encode};
+use crate::amount::Amount;
+use crate::orders::{Order, OrderError, SlippageLimit};
 use crate::storage::StorageSystem;
 
+/// Anything that can be held in an account and traded: fiat, crypto, stablecoins and tokens.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct Asset {
+    pub id: u64,
+    pub symbol: String,
+    /// Display name, e.g. "Bitcoin".
+    pub name: String,
+    pub kind: AssetKind,
+    /// Number of decimal places amounts of the asset have.
+    pub precision: u8,
+    pub status: AssetStatus,
+}
+
+#[derive(Encode, Decode, Debug, PartialEq, Clone, Copy)]
+pub enum AssetKind {
+    Fiat,
+    Crypto,
+    Stablecoin,
+    Token,
+}
+
+/// Markets only accept orders while both of their assets are `Active`.
+#[derive(Encode, Decode, Debug, PartialEq, Clone, Copy)]
+pub enum AssetStatus {
+    Active,
+    Suspended,
+    Delisted,
+}
+
+/// Fiat currency as stored before `Asset`, only read to migrate old databases.
 #[derive(Encode, Decode, Debug)]
-pub struct Currency {
+pub(crate) struct Currency {
     pub id: u64,
     pub symbol: String,
 }
 
+/// Crypto currency as stored before `Asset`, only read to migrate old databases.
 #[derive(Encode, Decode, Debug)]
-pub struct CryptoCurrency {
+pub(crate) struct CryptoCurrency {
     pub id: u64,
     pub symbol: String,
 }
 
+/// Trading pair, the base asset is bought and sold for the quote asset.
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+pub struct Market {
+    pub id: u64,
+    pub base_asset_id: u64,
+    pub quote_asset_id: u64,
+    pub status: MarketStatus,
+    pub trading_rules: TradingRules,
+}
+
+/// Orders are only accepted while a market is `Trading`.
+#[derive(Encode, Decode, Debug, PartialEq, Clone, Copy)]
+pub enum MarketStatus {
+    PreOpen,
+    Trading,
+    Halted,
+    Delisted,
+}
+
+/// Limits every order of a market has to respect, `None` means no limit.
+#[derive(Debug, Default, Clone, Copy, Encode, Decode)]
+pub struct TradingRules {
+    /// Prices have to be a multiple of it.
+    pub tick_size: Option<Amount>,
+    /// Quantities have to be a multiple of it.
+    pub lot_size: Option<Amount>,
+    pub min_quantity: Option<Amount>,
+    pub max_quantity: Option<Amount>,
+    /// Minimum value of an order in the quote asset, checked where the price is known up front.
+    pub min_notional: Option<Amount>,
+}
+
+impl TradingRules {
+    pub fn validate(&self, order: &Order) -> Result<(), OrderError> {
+        if let Some(tick_size) = self.tick_size {
+            let worst_price = match order.slippage {
+                Some(SlippageLimit::WorstPrice(price)) => Some(price),
+                _ => None,
+            };
+            for price in [order.price_type.limit_price(), order.price_type.stop_price(), worst_price].into_iter().flatten() {
+                if !price.is_multiple_of(tick_size) {
+                    return Err(OrderError::TickSize { price, tick_size });
+                }
+            }
+        }
+
+        if let Some(quote_quantity) = order.quote_quantity {
+            // The base quantity of quote orders is only known once they are filled
+            return match self.min_notional {
+                Some(min_notional) if quote_quantity < min_notional => Err(OrderError::MinNotional { notional: quote_quantity, min_notional }),
+                _ => Ok(()),
+            };
+        }
+
+        if let Some(lot_size) = self.lot_size {
+            for quantity in [Some(order.quantity), order.display_quantity].into_iter().flatten() {
+                if !quantity.is_multiple_of(lot_size) {
+                    return Err(OrderError::LotSize { quantity, lot_size });
+                }
+            }
+        }
+        match self.min_quantity {
+            Some(min_quantity) if order.quantity < min_quantity => return Err(OrderError::MinQuantity { quantity: order.quantity, min_quantity }),
+            _ => {}
+        }
+        match self.max_quantity {
+            Some(max_quantity) if order.quantity > max_quantity => return Err(OrderError::MaxQuantity { quantity: order.quantity, max_quantity }),
+            _ => {}
+        }
+        match (self.min_notional, order.price_type.limit_price()) {
+            (Some(min_notional), Some(price)) if order.quantity * price < min_notional => {
+                Err(OrderError::MinNotional { notional: order.quantity * price, min_notional })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 pub struct AssetSystem {
-    pub last_currency_id: u64,
-    pub last_crypto_currency_id: u64,
+    pub last_asset_id: u64,
+    pub last_market_id: u64,
     pub storage_system: Arc<StorageSystem>,
 }
 
 impl AssetSystem {
     pub fn new(storage_system: Arc<StorageSystem>) -> AssetSystem {
-        let mut last_currency_id: u64 =0;
-        match storage_system.get_last_currency() {
+        let mut last_asset_id: u64 =0;
+        match storage_system.get_last_asset() {
             None => {}
-            Some(currency) => {
-                last_currency_id = currency.id;
+            Some(asset) => {
+                last_asset_id = asset.id;
             }
         }
-        let mut last_crypto_currency_id: u64 = 0;
-        match storage_system.get_last_crypto_currency() {
+        let mut last_market_id: u64 = 0;
+        match storage_system.get_last_market() {
             None => {}
-            Some(crypto_currency) => {
-                last_crypto_currency_id = crypto_currency.id;
+            Some(market) => {
+                last_market_id = market.id;
             }
         }
 
         AssetSystem {
-            last_currency_id,
-            last_crypto_currency_id,
+            last_asset_id,
+            last_market_id,
             storage_system,
         }
     }
 
-    pub fn create_currency(&mut self, mut currency: Currency) -> u64 {
-        self.last_currency_id += 1;
-        currency.id = self.last_currency_id;
-        self.storage_system.add_currency(&currency);
-        self.last_currency_id
+    pub fn create_asset(&mut self, mut asset: Asset) -> u64 {
+        self.last_asset_id += 1;
+        asset.id = self.last_asset_id;
+        self.storage_system.add_asset(&asset);
+        self.last_asset_id
+    }
+
+    pub fn get_asset(&self, asset_id: u64) -> Option<Asset> {
+        self.storage_system.get_asset(asset_id)
+    }
+
+    pub fn get_asset_by_symbol(&self, symbol: &str) -> Option<Asset> {
+        self.storage_system.load_assets().into_iter().find(|asset| asset.symbol == symbol)
+    }
+
+    pub fn get_assets(&self) -> Vec<Asset> {
+        let mut assets:Vec<Asset> = self.storage_system.load_assets();
+        assets.sort_by_key(|a| a.id);
+        assets
     }
 
-    pub fn create_crypto_currency(&mut self, mut crypto_currency: CryptoCurrency) -> u64 {
-        self.last_crypto_currency_id += 1;
-        crypto_currency.id = self.last_crypto_currency_id;
-        self.storage_system.add_crypto_currency(&crypto_currency);
-        self.last_crypto_currency_id
+    pub fn update_asset(&self, asset: &Asset) {
+        self.storage_system.update_asset(asset);
     }
 
-    pub fn get_currencies(&self) -> Vec<Currency> {
-        let mut currencies:Vec<Currency> = self.storage_system.load_currencies();
-        currencies.sort_by(|a, b| a.id.cmp(&b.id));
-        currencies
+    pub fn set_asset_status(&self, asset_id: u64, status: AssetStatus) -> Option<Asset> {
+        let mut asset = self.get_asset(asset_id)?;
+        asset.status = status;
+        self.update_asset(&asset);
+        Some(asset)
     }
 
-    pub fn get_crypto_currencies(&self) -> Vec<CryptoCurrency> {
-        let mut crypto_currencies:Vec<CryptoCurrency> = self.storage_system.load_crypto_currencies();
-        crypto_currencies.sort_by(|a, b| a.id.cmp(&b.id));
-        crypto_currencies
+    pub fn create_market(&mut self, mut market: Market) -> u64 {
+        assert!(market.base_asset_id != market.quote_asset_id);
+        self.last_market_id += 1;
+        market.id = self.last_market_id;
+        self.storage_system.add_market(&market);
+        self.last_market_id
     }
 
-}
\ No newline at end of file
+    pub fn get_market(&self, market_id: u64) -> Option<Market> {
+        self.storage_system.get_market(market_id)
+    }
+
+    pub fn get_markets(&self) -> Vec<Market> {
+        let mut markets:Vec<Market> = self.storage_system.load_markets();
+        markets.sort_by_key(|a| a.id);
+        markets
+    }
+
+    pub fn update_market(&self, market: &Market) {
+        self.storage_system.update_market(market);
+    }
+
+    pub fn set_market_status(&self, market_id: u64, status: MarketStatus) -> Option<Market> {
+        let mut market = self.get_market(market_id)?;
+        market.status = status;
+        self.update_market(&market);
+        Some(market)
+    }
+
+    pub fn delete_market(&self, market_id: u64) -> Option<Market> {
+        self.storage_system.remove_market(market_id)
+    }
+
+}
@@ -0,0 +1,86 @@
+use std::collections::{BTreeMap, VecDeque};
+use crate::amount::Amount;
+use crate::orders::{Order, PriceType, TradeType};
+
+/// One side of the order book.
+///
+/// Market orders waiting for liquidity come first, then limit orders grouped by price level
+/// (descending for bids, ascending for asks). Inside a level orders are kept in arrival order.
+#[derive(Debug)]
+pub struct BookSide {
+    pub trade_type: TradeType,
+    pub market_orders: VecDeque<Order>,
+    /// Orders by limit price.
+    pub levels: BTreeMap<Amount, VecDeque<Order>>,
+}
+
+impl BookSide {
+    pub fn new(trade_type: TradeType) -> BookSide {
+        BookSide {
+            trade_type,
+            market_orders: VecDeque::new(),
+            levels: BTreeMap::new(),
+        }
+    }
+
+    /// Puts the order at the back of its queue.
+    pub fn push(&mut self, order: Order) {
+        match order.price_type {
+            PriceType::Market => self.market_orders.push_back(order),
+            PriceType::Limit(price) => self.levels.entry(price).or_default().push_back(order),
+            PriceType::StopMarket(_) | PriceType::StopLimit { .. } | PriceType::TrailingStop(_) => panic!("Stop orders are not in the book until triggered"),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.market_orders.is_empty() && self.levels.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.market_orders.len() + self.levels.values().map(|level| level.len()).sum::<usize>()
+    }
+
+    /// Best limit price of this side.
+    pub fn best_price(&self) -> Option<Amount> {
+        let level = match self.trade_type {
+            TradeType::Buy => self.levels.keys().next_back(),
+            TradeType::Sell => self.levels.keys().next(),
+        };
+        level.copied()
+    }
+
+    /// Orders in price-time priority.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &Order> + '_> {
+        let levels: Box<dyn Iterator<Item = &VecDeque<Order>>> = match self.trade_type {
+            TradeType::Buy => Box::new(self.levels.values().rev()),
+            TradeType::Sell => Box::new(self.levels.values()),
+        };
+        Box::new(self.market_orders.iter().chain(levels.flatten()))
+    }
+
+    pub fn get(&self, order_id: u64) -> Option<&Order> {
+        self.iter().find(|o| o.id == order_id)
+    }
+
+    pub fn get_mut(&mut self, order_id: u64) -> Option<&mut Order> {
+        if let Some(order) = self.market_orders.iter_mut().find(|o| o.id == order_id) {
+            return Some(order);
+        }
+        self.levels.values_mut().flat_map(|level| level.iter_mut()).find(|o| o.id == order_id)
+    }
+
+    /// Removes the order from the book, dropping its price level once it is empty.
+    pub fn remove(&mut self, order_id: u64) -> Option<Order> {
+        if let Some(index) = self.market_orders.iter().position(|o| o.id == order_id) {
+            return self.market_orders.remove(index);
+        }
+        let (price, index) = self.levels.iter()
+            .find_map(|(price, level)| level.iter().position(|o| o.id == order_id).map(|index| (*price, index)))?;
+        let level = self.levels.get_mut(&price).unwrap();
+        let order = level.remove(index);
+        if level.is_empty() {
+            self.levels.remove(&price);
+        }
+        order
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bincode::{Decode, Encode};
+use crate::amount::Amount;
+use crate::orders::{Order, OrderHistoryType};
+use crate::storage::{Settlement, StorageSystem};
+
+/// Trading volume that decides the fee tier is summed over this many days.
+pub const VOLUME_WINDOW_DAYS: u64 = 30;
+/// How often `FeeSystem::update_fee_tiers` recomputes the tiers by default.
+pub const FEE_TIER_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Fees of a market as a fraction of what each side of a trade receives, e.g. 0.001 is 0.1%.
+/// Markets without a schedule trade without fees.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct FeeSchedule {
+    pub market_id: u64,
+    /// Charged to the order that was resting in the book.
+    pub maker_rate: Amount,
+    /// Charged to the order that traded against the book.
+    pub taker_rate: Amount,
+    /// Exchange account the fees are credited to and rebates are paid from. Rebates are paid in the asset
+    /// the maker receives, only as far as the account holds it.
+    pub fee_account_id: u64,
+    /// Lower rates for accounts trading more, in ascending order of `min_volume`.
+    /// Tier 0 are the rates above, tier 1 the first one of these and so on.
+    pub tiers: Vec<FeeTier>,
+}
+
+impl FeeSchedule {
+    pub fn rate(&self, liquidity: Liquidity, tier: usize) -> Amount {
+        let (maker_rate, taker_rate) = match tier.checked_sub(1).and_then(|index| self.tiers.get(index)) {
+            Some(fee_tier) => (fee_tier.maker_rate, fee_tier.taker_rate),
+            None => (self.maker_rate, self.taker_rate),
+        };
+        match liquidity {
+            Liquidity::Maker => maker_rate,
+            Liquidity::Taker => taker_rate,
+        }
+    }
+
+    /// Highest tier the volume reaches.
+    pub fn tier(&self, volume: Amount) -> usize {
+        self.tiers.iter().take_while(|fee_tier| volume >= fee_tier.min_volume).count()
+    }
+}
+
+/// Rates for accounts that traded at least `min_volume` of the quote asset in the market
+/// over the last `VOLUME_WINDOW_DAYS`. A negative maker rate pays a rebate.
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+pub struct FeeTier {
+    pub min_volume: Amount,
+    pub maker_rate: Amount,
+    pub taker_rate: Amount,
+}
+
+/// Whether an order added liquidity to the book or took it.
+#[derive(Encode, Decode, Debug, PartialEq, Clone, Copy)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// Fee charged for one fill of an order, in the asset the order received.
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+pub struct Fee {
+    pub id: u64,
+    pub order_id: u64,
+    pub account_id: u64,
+    pub market_id: u64,
+    pub asset_id: u64,
+    pub liquidity: Liquidity,
+    pub rate: Amount,
+    pub amount: Amount,
+    /// Journal entry moving the fee to the fee account.
+    pub journal_entry_id: u64,
+    pub timestamp: SystemTime,
+}
+
+pub struct FeeSystem {
+    pub fee_last_id: u64,
+    /// Time between two recomputations of the fee tiers.
+    pub fee_tier_interval: Duration,
+    pub fee_tiers_updated_at: Option<SystemTime>,
+    /// Last order history counted into `daily_volumes`, later ones are read by the next update.
+    pub order_history_last_id: u64,
+    /// Quote asset traded per account, market and day since the Unix epoch, within the volume window.
+    pub daily_volumes: BTreeMap<(u64, u64, u64), Amount>,
+    /// Fee tier per account and market, accounts without one are in tier 0.
+    pub fee_tiers: HashMap<(u64, u64), usize>,
+    pub storage_system: Arc<StorageSystem>,
+}
+
+impl FeeSystem {
+    pub fn new(storage_system: Arc<StorageSystem>) -> FeeSystem {
+        let mut fee_last_id = 0;
+        match storage_system.get_last_fee() {
+            None => {}
+            Some(fee) => {
+                fee_last_id = fee.id;
+            }
+        }
+
+        let mut fee_system = FeeSystem {
+            fee_last_id,
+            fee_tier_interval: FEE_TIER_INTERVAL,
+            fee_tiers_updated_at: None,
+            order_history_last_id: 0,
+            daily_volumes: BTreeMap::new(),
+            fee_tiers: HashMap::new(),
+            storage_system,
+        };
+        // Tiers earned before a restart apply right away
+        fee_system.recompute_fee_tiers(SystemTime::now());
+        fee_system
+    }
+
+    /// Replaces the fee schedule of the market, the tiers apply from the next `update_fee_tiers`.
+    pub fn set_fee_schedule(&self, fee_schedule: &FeeSchedule) {
+        let rates = fee_schedule.tiers.iter().flat_map(|fee_tier| [fee_tier.maker_rate, fee_tier.taker_rate]);
+        assert!([fee_schedule.maker_rate, fee_schedule.taker_rate].into_iter().chain(rates).all(|rate| rate.abs() < Amount::ONE), "Fee rates have to be between -1 and 1");
+        assert!(fee_schedule.tiers.windows(2).all(|tiers| tiers[0].min_volume < tiers[1].min_volume), "Fee tiers have to be in ascending order of volume");
+        self.storage_system.set_fee_schedule(fee_schedule);
+    }
+
+    pub fn get_fee_schedule(&self, market_id: u64) -> Option<FeeSchedule> {
+        self.storage_system.get_fee_schedule(market_id)
+    }
+
+    pub fn remove_fee_schedule(&self, market_id: u64) -> Option<FeeSchedule> {
+        self.storage_system.remove_fee_schedule(market_id)
+    }
+
+    /// Adds the fee to the settlement with a new id.
+    pub fn stage_fee(&mut self, settlement: &mut Settlement, mut fee: Fee) -> u64 {
+        self.fee_last_id += 1;
+        fee.id = self.fee_last_id;
+        settlement.fees.push(fee);
+        self.fee_last_id
+    }
+
+    pub fn get_fees_by_order_id(&self, order_id: u64) -> Vec<Fee> {
+        self.storage_system.get_fees_by_order_id(order_id)
+    }
+
+    pub fn get_fees_by_account_id(&self, account_id: u64) -> Vec<Fee> {
+        self.storage_system.get_fees_by_account_id(account_id)
+    }
+
+    pub fn get_fee_tier(&self, account_id: u64, market_id: u64) -> usize {
+        self.fee_tiers.get(&(account_id, market_id)).copied().unwrap_or(0)
+    }
+
+    /// Quote asset the account traded in the market within the volume window, as of the last update.
+    pub fn get_trading_volume(&self, account_id: u64, market_id: u64) -> Amount {
+        self.daily_volumes.range((account_id, market_id, 0)..=(account_id, market_id, u64::MAX)).map(|(_, volume)| *volume).sum()
+    }
+
+    /// Recomputes the fee tiers if `fee_tier_interval` passed since the last time.
+    pub fn update_fee_tiers(&mut self, now: SystemTime) {
+        match self.fee_tiers_updated_at {
+            Some(updated_at) if now < updated_at + self.fee_tier_interval => {}
+            _ => self.recompute_fee_tiers(now),
+        }
+    }
+
+    /// Adds the fills stored since the last recomputation to the daily volumes and drops the days that left the window,
+    /// so only new order histories are read. The first time only the order histories within the window are read.
+    pub fn recompute_fee_tiers(&mut self, now: SystemTime) {
+        let first_day = day(now).saturating_sub(VOLUME_WINDOW_DAYS - 1);
+        let order_histories = match self.fee_tiers_updated_at {
+            None => {
+                // Continue after the newest row even if none of them is within the window
+                self.order_history_last_id = self.storage_system.get_last_order_history().map(|order_history| order_history.id).unwrap_or(0);
+                self.storage_system.load_order_histories_since(UNIX_EPOCH + Duration::from_secs(first_day * SECONDS_PER_DAY))
+            }
+            Some(_) => self.storage_system.load_order_histories_after(self.order_history_last_id),
+        };
+        let mut orders: HashMap<u64, Order> = HashMap::new();
+        for order_history in order_histories {
+            self.order_history_last_id = order_history.id;
+            let (OrderHistoryType::Fill, Some(amount)) = (order_history.history_type, order_history.amount) else {
+                continue;
+            };
+            let order = orders.entry(order_history.order_id).or_insert_with(|| self.storage_system.get_order(order_history.order_id).unwrap());
+            *self.daily_volumes.entry((order.account_id, order.market_id, day(order_history.timestamp))).or_default() += amount;
+        }
+        self.daily_volumes.retain(|(_, _, day), _| *day >= first_day);
+
+        let mut volumes: HashMap<(u64, u64), Amount> = HashMap::new();
+        for ((account_id, market_id, _), volume) in self.daily_volumes.iter() {
+            *volumes.entry((*account_id, *market_id)).or_default() += *volume;
+        }
+        let mut fee_schedules: HashMap<u64, Option<FeeSchedule>> = HashMap::new();
+        self.fee_tiers = volumes.into_iter()
+            .filter_map(|((account_id, market_id), volume)| {
+                let fee_schedule = fee_schedules.entry(market_id).or_insert_with(|| self.storage_system.get_fee_schedule(market_id));
+                let tier = fee_schedule.as_ref()?.tier(volume);
+                Some(((account_id, market_id), tier))
+            })
+            .filter(|(_, tier)| *tier > 0)
+            .collect();
+        self.fee_tiers_updated_at = Some(now);
+    }
+}
+
+/// Days since the Unix epoch.
+fn day(timestamp: SystemTime) -> u64 {
+    timestamp.duration_since(UNIX_EPOCH).unwrap().as_secs() / SECONDS_PER_DAY
+}
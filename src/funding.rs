@@ -0,0 +1,104 @@
+use std::time::SystemTime;
+use bincode::{Decode, Encode};
+use crate::amount::Amount;
+
+/// Funds coming into an account from a blockchain or bank, credited once the transaction is confirmed.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct Deposit {
+    pub id: u64,
+    pub account_id: u64,
+    pub asset_id: u64,
+    pub amount: Amount,
+    /// Transaction hash or bank reference.
+    pub reference: String,
+    pub confirmations: u32,
+    /// Confirmations needed before the deposit is `Confirmed`, zero for bank transfers.
+    pub required_confirmations: u32,
+    pub status: FundingStatus,
+    pub timestamp: SystemTime,
+}
+
+/// Funds leaving an account, held from the request until the transaction is confirmed or the withdrawal rejected.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct Withdrawal {
+    pub id: u64,
+    pub account_id: u64,
+    pub asset_id: u64,
+    pub amount: Amount,
+    /// Address or bank account the funds are sent to.
+    pub destination: String,
+    /// Transaction hash or bank reference, known once the withdrawal is sent.
+    pub reference: Option<String>,
+    pub confirmations: u32,
+    /// Confirmations needed before the withdrawal is `Completed`, zero for bank transfers.
+    pub required_confirmations: u32,
+    pub status: FundingStatus,
+    pub timestamp: SystemTime,
+}
+
+/// Deposits go `Pending` (waiting for confirmations) → `Confirmed` → `Completed` (credited),
+/// withdrawals `Pending` (waiting for approval) → `Confirmed` (approved and sent) → `Completed` (debited).
+/// Both can be `Rejected` before they are completed.
+#[derive(Encode, Decode, Debug, PartialEq, Clone, Copy)]
+pub enum FundingStatus {
+    Pending,
+    Confirmed,
+    Rejected,
+    Completed,
+}
+
+/// What the adapter knows about a transaction.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransactionStatus {
+    /// Confirmations of the transaction so far.
+    Confirmations(u32),
+    /// The adapter doesn't know the transaction (yet), it can still confirm.
+    Unknown,
+    /// The transaction failed or was returned, it will never confirm.
+    Failed,
+}
+
+/// Blockchain node or bank the funds of an asset move in and out of the exchange through.
+pub trait FundingAdapter {
+    /// Confirmations a transaction of the asset needs, zero for bank transfers.
+    fn required_confirmations(&self, asset_id: u64) -> u32;
+
+    fn get_confirmations(&self, reference: &str) -> TransactionStatus;
+
+    /// Sends the funds of an approved withdrawal, returns the reference of the transaction.
+    fn send_withdrawal(&mut self, withdrawal: &Withdrawal) -> Result<String, String>;
+}
+
+/// Why a deposit or withdrawal was not accepted.
+#[derive(Debug, PartialEq, Clone)]
+pub enum FundingError {
+    AssetNotFound { asset_id: u64 },
+    /// The amount is not positive or has more decimal places than its asset.
+    InvalidAmount { amount: Amount, asset_id: u64 },
+    InsufficientFunds { asset_id: u64, required: Amount, available: Amount },
+    DepositNotFound { deposit_id: u64 },
+    /// A deposit of the asset with the same transaction was already recorded.
+    DuplicateReference { reference: String, deposit_id: u64 },
+    WithdrawalNotFound { withdrawal_id: u64 },
+    /// The deposit or withdrawal can't make this step from its current status.
+    InvalidStatus { status: FundingStatus },
+    /// The adapter couldn't send the withdrawal.
+    Adapter(String),
+}
+
+impl std::fmt::Display for FundingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FundingError::AssetNotFound { asset_id } => write!(f, "asset {asset_id} not found"),
+            FundingError::InvalidAmount { amount, asset_id } => write!(f, "{amount} is not a valid amount of asset {asset_id}"),
+            FundingError::InsufficientFunds { asset_id, required, available } => write!(f, "{required} of asset {asset_id} required, {available} available"),
+            FundingError::DepositNotFound { deposit_id } => write!(f, "deposit {deposit_id} not found"),
+            FundingError::DuplicateReference { reference, deposit_id } => write!(f, "reference {reference} already used by deposit {deposit_id}"),
+            FundingError::WithdrawalNotFound { withdrawal_id } => write!(f, "withdrawal {withdrawal_id} not found"),
+            FundingError::InvalidStatus { status } => write!(f, "not possible in status {status:?}"),
+            FundingError::Adapter(message) => write!(f, "adapter error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for FundingError {}
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+use bincode::{Decode, Encode};
+use crate::amount::Amount;
+use crate::storage::{Settlement, StorageSystem};
+
+/// One balance movement, made of postings that add up to zero per asset.
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub entry_type: EntryType,
+    pub reference: JournalReference,
+    pub timestamp: SystemTime,
+}
+
+/// Change of one ledger account's balance of an asset, positive amounts increase it.
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+pub struct Posting {
+    pub id: u64,
+    pub journal_entry_id: u64,
+    pub ledger_account: LedgerAccount,
+    pub asset_id: u64,
+    pub amount: Amount,
+}
+
+#[derive(Encode, Decode, Debug, PartialEq, Clone, Copy)]
+pub enum EntryType {
+    Deposit,
+    Withdrawal,
+    /// Both legs of a trade.
+    Trade,
+    /// Between two accounts of the exchange.
+    Transfer,
+    /// Trading fee moved to the fee account, or a rebate paid out of it.
+    Fee,
+}
+
+/// What caused a journal entry.
+#[derive(Encode, Decode, Debug, PartialEq, Clone, Copy)]
+pub enum JournalReference {
+    None,
+    /// The buy and sell order that matched.
+    Match { buy_order_id: u64, sell_order_id: u64 },
+    Deposit { deposit_id: u64 },
+    Withdrawal { withdrawal_id: u64 },
+}
+
+#[derive(Encode, Decode, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum LedgerAccount {
+    /// Balance of an account of the exchange.
+    Account(u64),
+    /// Funds outside the exchange, the other side of deposits and withdrawals.
+    External,
+}
+
+/// Why the ledger doesn't add up.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LedgerError {
+    /// The postings of an asset don't sum to zero.
+    Unbalanced { asset_id: u64, sum: Amount },
+    /// An account balance differs from the sum of its postings.
+    BalanceMismatch { account_id: u64, asset_id: u64, balance: Amount, postings: Amount },
+}
+
+impl std::fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerError::Unbalanced { asset_id, sum } => write!(f, "postings of asset {asset_id} sum to {sum}"),
+            LedgerError::BalanceMismatch { account_id, asset_id, balance, postings } => {
+                write!(f, "balance {balance} of asset {asset_id} in account {account_id} differs from its postings {postings}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+pub struct LedgerSystem {
+    pub journal_entry_last_id: u64,
+    pub posting_last_id: u64,
+    pub storage_system: Arc<StorageSystem>,
+}
+
+impl LedgerSystem {
+    pub fn new(storage_system: Arc<StorageSystem>) -> LedgerSystem {
+        let mut journal_entry_last_id = 0;
+        match storage_system.get_last_journal_entry() {
+            None => {}
+            Some(journal_entry) => {
+                journal_entry_last_id = journal_entry.id;
+            }
+        }
+        let mut posting_last_id = 0;
+        match storage_system.get_last_posting() {
+            None => {}
+            Some(posting) => {
+                posting_last_id = posting.id;
+            }
+        }
+
+        LedgerSystem {
+            journal_entry_last_id,
+            posting_last_id,
+            storage_system,
+        }
+    }
+
+    /// Adds the journal entry and its postings to the settlement, `postings` are (ledger account, asset id, amount).
+    /// Panics if the postings of an asset don't sum to zero.
+    pub fn stage_journal_entry(&mut self, settlement: &mut Settlement, entry_type: EntryType, reference: JournalReference, postings: &[(LedgerAccount, u64, Amount)]) -> u64 {
+        let mut sums: BTreeMap<u64, Amount> = BTreeMap::new();
+        for (_, asset_id, amount) in postings {
+            *sums.entry(*asset_id).or_default() += *amount;
+        }
+        assert!(sums.values().all(|sum| sum.is_zero()), "Unbalanced journal entry {entry_type:?}: {postings:?}");
+
+        self.journal_entry_last_id += 1;
+        let journal_entry_id = self.journal_entry_last_id;
+        settlement.journal_entries.push(JournalEntry { id: journal_entry_id, entry_type, reference, timestamp: SystemTime::now() });
+        for (ledger_account, asset_id, amount) in postings {
+            self.posting_last_id += 1;
+            settlement.postings.push(Posting { id: self.posting_last_id, journal_entry_id, ledger_account: *ledger_account, asset_id: *asset_id, amount: *amount });
+        }
+        journal_entry_id
+    }
+
+    pub fn get_journal_entries(&self) -> Vec<JournalEntry> {
+        self.storage_system.load_journal_entries()
+    }
+
+    pub fn get_postings(&self, journal_entry_id: u64) -> Vec<Posting> {
+        self.storage_system.get_postings_by_journal_entry_id(journal_entry_id)
+    }
+
+    /// Sum of all postings per asset id, every one of them is zero in a consistent ledger.
+    pub fn trial_balance(&self) -> BTreeMap<u64, Amount> {
+        let mut sums = BTreeMap::new();
+        for posting in self.storage_system.load_postings() {
+            *sums.entry(posting.asset_id).or_default() += posting.amount;
+        }
+        sums
+    }
+
+    /// Checks that the postings of every asset sum to zero and that every account balance is the sum of its postings.
+    pub fn check_trial_balance(&self) -> Result<(), LedgerError> {
+        if let Some((asset_id, sum)) = self.trial_balance().into_iter().find(|(_, sum)| !sum.is_zero()) {
+            return Err(LedgerError::Unbalanced { asset_id, sum });
+        }
+        let mut account_sums: BTreeMap<(u64, u64), Amount> = BTreeMap::new();
+        for posting in self.storage_system.load_postings() {
+            if let LedgerAccount::Account(account_id) = posting.ledger_account {
+                *account_sums.entry((account_id, posting.asset_id)).or_default() += posting.amount;
+            }
+        }
+        for account_asset in self.storage_system.load_account_assets() {
+            let postings = account_sums.remove(&(account_asset.account_id, account_asset.asset_id)).unwrap_or_default();
+            if account_asset.balance != postings {
+                return Err(LedgerError::BalanceMismatch { account_id: account_asset.account_id, asset_id: account_asset.asset_id, balance: account_asset.balance, postings });
+            }
+        }
+        // Postings of accounts without a balance row
+        match account_sums.into_iter().find(|(_, postings)| !postings.is_zero()) {
+            Some(((account_id, asset_id), postings)) => Err(LedgerError::BalanceMismatch { account_id, asset_id, balance: Amount::ZERO, postings }),
+            None => Ok(()),
+        }
+    }
+}
@@ -1,8 +1,11 @@
-//!
 // #![doc = include_str!("../README.md")]
-//!
+pub mod amount;
 pub mod assets;
 pub mod accounts;
+pub mod ledger;
+pub mod fees;
+pub mod funding;
 pub mod orders;
+pub mod book;
 pub mod matcher;
 pub mod storage;
@@ -1,143 +1,809 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use std::time::{SystemTime};
 use core_affinity::CoreId;
-use crossbeam_queue::ArrayQueue;
+use crossbeam_queue::SegQueue;
 use tracing::{Level, span};
-use crate::orders::{Order, PriceType};
+use crate::amount::Amount;
+use crate::book::BookSide;
+use crate::orders::{ExecutionType, Order, PriceType, SlippageLimit, TimeInForce, TradeType, TrailingOffset};
 
 #[derive(Debug)]
 pub struct OrderMatcher {
-    pub crypto_currency_id: u64,
-    pub currency_id: u64,
-    pub buy_orders: HashMap<u64,Order>,
-    pub sell_orders: HashMap<u64,Order>,
+    pub market_id: u64,
+    /// Decimal places of the base asset, quantities bought with a quote quantity are truncated to it.
+    pub base_precision: u8,
+    /// Decimal places of the quote asset, trade amounts are truncated to it.
+    pub quote_precision: u8,
+    pub incoming_orders: VecDeque<Order>,
+    pub bids: BookSide,
+    pub asks: BookSide,
+    /// Stop orders waiting for their stop price, in arrival order.
+    pub stop_orders: Vec<Order>,
+    /// Current stop price of every trailing stop order, once there was a trade to follow.
+    pub trailing_stop_prices: HashMap<u64, Amount>,
+    /// Hidden quantity of iceberg orders behind the slice shown in the book.
+    pub iceberg_reserves: HashMap<u64, Amount>,
+    /// Orders of every one-cancels-other group that hasn't traded yet.
+    pub oco_groups: HashMap<u64, [u64; 2]>,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub last_price: Option<Amount>,
 }
 
+#[derive(Debug)]
 pub struct OrderMatch {
     pub buy_order_id: u64,
     pub sell_order_id: u64,
-    pub quantity: f64,
-    pub price: f64,
+    pub quantity: Amount,
+    pub price: Amount,
+    /// Quote asset paid by the buyer, `quantity * price` truncated to the precision of the quote asset.
+    pub amount: Amount,
+    /// Side of the taker, the order that traded against the one resting in the book.
+    pub aggressor: TradeType,
     pub timestamp: SystemTime,
 }
 
+/// Everything the matcher reports back, in the order it happened.
+#[derive(Debug)]
+pub enum MatcherEvent {
+    Match(OrderMatch),
+    /// The open quantity of the order was cancelled.
+    Cancelled { order_id: u64, quantity: Amount, timestamp: SystemTime },
+    /// A good-till-date order reached its deadline.
+    Expired { order_id: u64, quantity: Amount, timestamp: SystemTime },
+    /// The order was not accepted and never traded.
+    Rejected { order_id: u64, reason: RejectReason, timestamp: SystemTime },
+    /// The order was changed, `quantity` is the new open quantity.
+    Amended { order_id: u64, price_type: PriceType, quantity: Amount, priority_kept: bool, timestamp: SystemTime },
+    /// Orders of the same account would have traded with each other, the orders were handled
+    /// according to the matcher's `SelfTradePrevention`.
+    SelfTradePrevented { taker_order_id: u64, maker_order_id: u64, quantity: Amount, timestamp: SystemTime },
+    /// A market order stopped filling at its slippage protection price, `quantity` is what is left of it.
+    /// The rest is cancelled or rests as a limit order at `price`.
+    SlippageProtected { order_id: u64, price: Amount, quantity: Amount, timestamp: SystemTime },
+    /// The stop price of a stop order was reached and it entered the book as `price_type`.
+    Triggered { order_id: u64, price_type: PriceType, quantity: Amount, timestamp: SystemTime },
+    /// The stop price of a trailing stop order followed the trade price.
+    TrailingStopMoved { order_id: u64, stop_price: Amount, timestamp: SystemTime },
+    /// The amendment can't be applied to the order, which stays as it was.
+    AmendRejected { order_id: u64, timestamp: SystemTime },
+    /// A command referred to an order that is not in the book (anymore).
+    OrderNotFound { order_id: u64, timestamp: SystemTime },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RejectReason {
+    FillOrKill,
+    /// A post-only order would have taken liquidity.
+    PostOnly,
+    /// A market order's maximum deviation had no best or last price to start from.
+    NoReferencePrice,
+}
+
+/// What happens when an incoming order would trade with a resting order of the same account.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SelfTradePrevention {
+    /// The orders trade with each other.
+    Allow,
+    /// The incoming order is cancelled.
+    CancelNewest,
+    /// The resting order is cancelled.
+    CancelOldest,
+    CancelBoth,
+    /// Both orders are reduced by the smaller quantity, the smaller one is cancelled.
+    DecrementAndCancel,
+}
+
+/// Requests sent to the matcher thread.
+#[derive(Debug)]
+pub enum MatcherCommand {
+    AddOrder(Order),
+    AddOcoOrders(Box<(Order, Order)>),
+    CancelOrder(u64),
+    AmendOrder { order_id: u64, price: Option<Amount>, quantity: Option<Amount> },
+    SetSelfTradePrevention(SelfTradePrevention),
+}
+
 impl OrderMatcher {
-    pub fn new(crypto_currency_id: u64, currency_id: u64) -> OrderMatcher {
+    pub fn new(market_id: u64, base_precision: u8, quote_precision: u8) -> OrderMatcher {
         OrderMatcher {
-            crypto_currency_id,
-            currency_id,
-            buy_orders: Default::default(),
-            sell_orders: Default::default(),
+            market_id,
+            base_precision,
+            quote_precision,
+            incoming_orders: VecDeque::new(),
+            bids: BookSide::new(TradeType::Buy),
+            asks: BookSide::new(TradeType::Sell),
+            stop_orders: Vec::new(),
+            trailing_stop_prices: HashMap::new(),
+            iceberg_reserves: HashMap::new(),
+            oco_groups: HashMap::new(),
+            self_trade_prevention: SelfTradePrevention::Allow,
+            last_price: None,
         }
     }
 
     pub fn add_order(&mut self, order: Order) {
-        assert!(order.crypto_currency_id == self.crypto_currency_id);
-        assert!(order.currency_id == self.currency_id);
-        if order.trade_type == crate::orders::TradeType::Buy {
-            self.buy_orders.insert(order.id, order);
+        assert!(order.market_id == self.market_id);
+        self.incoming_orders.push_back(order);
+    }
+
+    /// Adds both orders of a one-cancels-other group.
+    pub fn add_oco_orders(&mut self, first: Order, second: Order) {
+        let oco_group_id = first.oco_group_id.unwrap();
+        assert!(second.oco_group_id == Some(oco_group_id));
+        self.oco_groups.insert(oco_group_id, [first.id, second.id]);
+        self.add_order(first);
+        self.add_order(second);
+    }
+
+    /// Takes the order off the book, or out of the incoming orders if it wasn't matched yet.
+    pub fn cancel_order(&mut self, order_id: u64) -> MatcherEvent {
+        let timestamp = SystemTime::now();
+        match self.remove_order(order_id) {
+            Some(order) => MatcherEvent::Cancelled { order_id, quantity: order.quantity, timestamp },
+            None => MatcherEvent::OrderNotFound { order_id, timestamp },
+        }
+    }
+
+    /// Changes the limit price and/or the open quantity of an order.
+    /// Reducing the quantity keeps time priority, a new price or a bigger quantity sends the order
+    /// to the back of the queue, and it gets matched again like a new order.
+    pub fn amend_order(&mut self, order_id: u64, price: Option<Amount>, quantity: Option<Amount>) -> MatcherEvent {
+        let timestamp = SystemTime::now();
+        let Some(order) = self.get_order_mut(order_id).map(|order| *order) else {
+            return MatcherEvent::OrderNotFound { order_id, timestamp };
+        };
+        let price_type = match (order.price_type, price) {
+            (price_type, None) => price_type,
+            (PriceType::Limit(_), Some(price)) => PriceType::Limit(price),
+            (_, Some(_)) => return MatcherEvent::AmendRejected { order_id, timestamp },
+        };
+        let reserve = self.iceberg_reserves.get(&order_id).copied().unwrap_or(Amount::ZERO);
+        let open_quantity = order.quantity + reserve;
+        let quantity = quantity.unwrap_or(open_quantity);
+        if !quantity.is_positive() || !quantity.has_precision(self.base_precision as u32) {
+            return MatcherEvent::AmendRejected { order_id, timestamp };
+        }
+
+        let priority_kept = price_type == order.price_type && quantity <= open_quantity;
+        if priority_kept {
+            // Icebergs give up hidden quantity first
+            let shown_quantity = order.quantity.min(quantity);
+            self.get_order_mut(order_id).unwrap().quantity = shown_quantity;
+            if quantity > shown_quantity {
+                self.iceberg_reserves.insert(order_id, quantity - shown_quantity);
+            } else {
+                self.iceberg_reserves.remove(&order_id);
+            }
         } else {
-            self.sell_orders.insert(order.id, order);
+            let mut order = self.remove_order(order_id).unwrap();
+            order.price_type = price_type;
+            order.quantity = quantity;
+            self.incoming_orders.push_back(order);
+        }
+        MatcherEvent::Amended { order_id, price_type, quantity, priority_kept, timestamp }
+    }
+
+    fn get_order_mut(&mut self, order_id: u64) -> Option<&mut Order> {
+        if let Some(order) = self.incoming_orders.iter_mut().find(|order| order.id == order_id) {
+            return Some(order);
+        }
+        if let Some(order) = self.stop_orders.iter_mut().find(|order| order.id == order_id) {
+            return Some(order);
+        }
+        match self.bids.get_mut(order_id) {
+            Some(order) => Some(order),
+            None => self.asks.get_mut(order_id),
+        }
+    }
+
+    fn remove_order(&mut self, order_id: u64) -> Option<Order> {
+        if let Some(index) = self.incoming_orders.iter().position(|order| order.id == order_id) {
+            return self.incoming_orders.remove(index);
+        }
+        if let Some(index) = self.stop_orders.iter().position(|order| order.id == order_id) {
+            self.trailing_stop_prices.remove(&order_id);
+            return Some(self.stop_orders.remove(index));
         }
+        let mut order = self.bids.remove(order_id).or_else(|| self.asks.remove(order_id))?;
+        order.quantity += self.iceberg_reserves.remove(&order_id).unwrap_or(Amount::ZERO);
+        Some(order)
     }
 
-    pub fn match_orders(&mut self) -> Vec<OrderMatch> {
+    /// Puts the order in the book, an iceberg order only shows its display quantity.
+    fn rest_order(&mut self, mut order: Order) {
+        if let Some(reserve) = show_slice(&mut order) {
+            self.iceberg_reserves.insert(order.id, reserve);
+        }
+        match order.trade_type {
+            TradeType::Buy => self.bids.push(order),
+            TradeType::Sell => self.asks.push(order),
+        }
+    }
+
+    /// Shows the next slice of a filled iceberg order at the back of its price level.
+    fn replenish_iceberg(&mut self, mut order: Order) {
+        if let Some(reserve) = self.iceberg_reserves.remove(&order.id) {
+            order.quantity = reserve;
+            self.rest_order(order);
+        }
+    }
+
+    /// Matches incoming orders in arrival order against the book by price-time priority.
+    /// Whatever is left of an incoming order rests in the book unless its time in force says otherwise.
+    pub fn match_orders(&mut self) -> Vec<MatcherEvent> {
         self.print_orders("Before Matching");
 
-        let mut matches = Vec::new();
+        let mut events = Vec::new();
+        let now = SystemTime::now();
+        self.expire_orders(now, &mut events);
 
-        let mut buy_orders: Vec<_> = self.buy_orders.drain().map(|(_, o)| o).collect();
-        let mut sell_orders: Vec<_> = self.sell_orders.drain().map(|(_, o)| o).collect();
+        // Trades can trigger stop orders, which can trade and trigger more of them
+        loop {
+            while let Some(order) = self.incoming_orders.pop_front() {
+                self.process_incoming_order(order, now, &mut events);
+                self.trigger_stop_orders(&mut events);
+            }
+            self.match_resting_full_orders(&mut events);
+            self.trigger_stop_orders(&mut events);
+            if self.incoming_orders.is_empty() {
+                break;
+            }
+        }
 
-        // Matching logic
-        let mut i = 0;
-        let mut j = 0;
+        self.print_orders("After Matching");
+        events
+    }
 
-        while i < buy_orders.len() && j < sell_orders.len() {
-            let buy = &buy_orders[i];
-            let sell = &sell_orders[j];
+    fn process_incoming_order(&mut self, order: Order, now: SystemTime, events: &mut Vec<MatcherEvent>) {
+        if is_expired(&order, now) {
+            events.push(MatcherEvent::Expired { order_id: order.id, quantity: order.quantity, timestamp: now });
+            return;
+        }
+        if order.price_type.is_stop() {
+            self.stop_orders.push(order);
+            if let Some(last_price) = self.last_price {
+                self.follow_trailing_stops(last_price, events);
+            }
+            return;
+        }
+        if order.post_only && (order.price_type == PriceType::Market || crossing_makers(self.makers(order.trade_type), &order).next().is_some()) {
+            events.push(MatcherEvent::Rejected { order_id: order.id, reason: RejectReason::PostOnly, timestamp: now });
+            return;
+        }
+        // Protected market orders trade like a limit order at the protection price
+        let mut order = order;
+        let protection_price = match (order.price_type, order.slippage) {
+            (PriceType::Market, Some(slippage)) => {
+                let reference_price = self.makers(order.trade_type).best_price().or(self.last_price);
+                let Some(price) = protection_price(order.trade_type, slippage, reference_price) else {
+                    events.push(MatcherEvent::Rejected { order_id: order.id, reason: RejectReason::NoReferencePrice, timestamp: now });
+                    return;
+                };
+                let price = price.round_dp(self.quote_precision as u32);
+                order.price_type = PriceType::Limit(price);
+                Some(price)
+            }
+            _ => None,
+        };
+        if order.time_in_force == TimeInForce::FillOrKill && self.fillable_quantity(&order) < order.quantity {
+            events.push(MatcherEvent::Rejected { order_id: order.id, reason: RejectReason::FillOrKill, timestamp: now });
+            return;
+        }
+        let mut order = self.take_liquidity(order, events);
+        if let Some(price) = protection_price {
+            if has_open_quantity(&order) {
+                events.push(MatcherEvent::SlippageProtected { order_id: order.id, price, quantity: order.quantity, timestamp: SystemTime::now() });
+                // The rest costs at most the protection price now, what a capped buy holds beyond that is released
+                if order.is_capped_by_hold() {
+                    order.held = order.held.min((order.quantity * price).round_up_dp(self.quote_precision as u32));
+                }
+            }
+        }
+        if order.quote_quantity.is_some() {
+            // Whatever is left of the budget once the book runs out is not spent
+            if has_open_quantity(&order) {
+                events.push(MatcherEvent::Cancelled { order_id: order.id, quantity: order.quantity, timestamp: SystemTime::now() });
+            }
+        } else if order.quantity.is_positive() {
+            // A buy capped by its hold is done once the hold is spent
+            if order.time_in_force == TimeInForce::ImmediateOrCancel || !has_open_quantity(&order) {
+                events.push(MatcherEvent::Cancelled { order_id: order.id, quantity: order.quantity, timestamp: SystemTime::now() });
+            } else {
+                self.rest_order(order);
+            }
+        }
+    }
 
-            // Check for match
-            match (buy.price_type, sell.price_type) {
-                (PriceType::Market, PriceType::Limit(sell_price))   => {
-                    // Calculate matched quantity
-                    let matched_quantity = buy.quantity.min(sell.quantity);
+    /// Moves stop orders whose stop price was reached by the last trade to the front of the incoming orders.
+    fn trigger_stop_orders(&mut self, events: &mut Vec<MatcherEvent>) {
+        let Some(last_price) = self.last_price else {
+            return;
+        };
+        let trailing_stop_prices = &self.trailing_stop_prices;
+        let (mut triggered, waiting): (Vec<Order>, Vec<Order>) = self.stop_orders.drain(..)
+            .partition(|order| is_stop_reached(order, trailing_stop_prices, last_price));
+        self.stop_orders = waiting;
 
-                    // Create match record
-                    matches.push(OrderMatch {
-                        buy_order_id: buy.id,
-                        sell_order_id: sell.id,
-                        quantity: matched_quantity,
-                        price: sell_price, // Execute at sell price
-                        timestamp: SystemTime::now(),
-                    });
+        let timestamp = SystemTime::now();
+        for order in triggered.iter_mut() {
+            self.trailing_stop_prices.remove(&order.id);
+            order.price_type = match order.price_type {
+                PriceType::StopLimit { limit_price, .. } => PriceType::Limit(limit_price),
+                _ => PriceType::Market,
+            };
+            events.push(MatcherEvent::Triggered { order_id: order.id, price_type: order.price_type, quantity: order.quantity, timestamp });
+        }
+        for order in triggered.into_iter().rev() {
+            self.incoming_orders.push_front(order);
+        }
+    }
 
-                    // Update quantities
-                    buy_orders[i].quantity -= matched_quantity;
-                    sell_orders[j].quantity -= matched_quantity;
-                    j += 1; // move to next sell order
-                    i += 1; // move to next buy order
-            },
-                _ => i += 1, // No match, move to next buy order
+    /// Removes good-till-date orders whose deadline has passed.
+    fn expire_orders(&mut self, now: SystemTime, events: &mut Vec<MatcherEvent>) {
+        for side in [&mut self.bids, &mut self.asks] {
+            let expired: Vec<u64> = side.iter().filter(|order| is_expired(order, now)).map(|order| order.id).collect();
+            for order_id in expired {
+                let order = side.remove(order_id).unwrap();
+                let quantity = order.quantity + self.iceberg_reserves.remove(&order_id).unwrap_or(Amount::ZERO);
+                events.push(MatcherEvent::Expired { order_id, quantity, timestamp: now });
             }
+        }
+        let (expired, waiting): (Vec<Order>, Vec<Order>) = self.stop_orders.drain(..)
+            .partition(|order| is_expired(order, now));
+        self.stop_orders = waiting;
+        for order in expired {
+            self.trailing_stop_prices.remove(&order.id);
+            events.push(MatcherEvent::Expired { order_id: order.id, quantity: order.quantity, timestamp: now });
+        }
+    }
+
+    fn prevent_self_trade(&mut self, taker: &mut Order, maker_id: u64, price: Amount, events: &mut Vec<MatcherEvent>) {
+        let timestamp = SystemTime::now();
+        let maker_quantity = self.makers(taker.trade_type).get(maker_id).unwrap().quantity
+            + self.iceberg_reserves.get(&maker_id).copied().unwrap_or(Amount::ZERO);
+        let taker_quantity = open_quantity(taker, price).trunc_dp(self.base_precision as u32);
+        let quantity = taker_quantity.min(maker_quantity);
+        events.push(MatcherEvent::SelfTradePrevented { taker_order_id: taker.id, maker_order_id: maker_id, quantity, timestamp });
 
+        let (cancel_taker, cancel_maker) = match self.self_trade_prevention {
+            SelfTradePrevention::Allow => (false, false),
+            SelfTradePrevention::CancelNewest => (true, false),
+            SelfTradePrevention::CancelOldest => (false, true),
+            SelfTradePrevention::CancelBoth => (true, true),
+            // A quote budget can't be decremented by a base quantity, the taker goes
+            SelfTradePrevention::DecrementAndCancel if taker.quote_quantity.is_some() => (true, maker_quantity <= taker_quantity),
+            SelfTradePrevention::DecrementAndCancel => (taker_quantity <= maker_quantity, maker_quantity <= taker_quantity),
+        };
+        if self.self_trade_prevention == SelfTradePrevention::DecrementAndCancel {
+            if !cancel_taker {
+                taker.quantity -= quantity;
+                events.push(MatcherEvent::Amended { order_id: taker.id, price_type: taker.price_type, quantity: taker.quantity, priority_kept: true, timestamp });
+            }
+            if !cancel_maker {
+                events.push(self.amend_order(maker_id, None, Some(maker_quantity - quantity)));
+            }
         }
+        if cancel_maker {
+            let maker = self.remove_order(maker_id).unwrap();
+            events.push(MatcherEvent::Cancelled { order_id: maker_id, quantity: maker.quantity, timestamp });
+        }
+        if cancel_taker {
+            events.push(MatcherEvent::Cancelled { order_id: taker.id, quantity: taker.quantity, timestamp });
+            taker.quantity = Amount::ZERO;
+            taker.quote_quantity = taker.quote_quantity.map(|_| Amount::ZERO);
+        }
+    }
 
-        // Any remaining unmatched orders are put back into the HashMap
-        for buy in buy_orders {
-            if buy.quantity > 0.0 {
-                self.buy_orders.insert(buy.id, buy);
+    /// Cancels the rest of the one-cancels-other group once one of its orders traded.
+    fn cancel_oco_group(&mut self, oco_group_id: u64, traded_order_id: u64, events: &mut Vec<MatcherEvent>) {
+        let Some(order_ids) = self.oco_groups.remove(&oco_group_id) else {
+            return;
+        };
+        for order_id in order_ids.into_iter().filter(|order_id| *order_id != traded_order_id) {
+            if let Some(order) = self.remove_order(order_id) {
+                events.push(MatcherEvent::Cancelled { order_id, quantity: order.quantity, timestamp: SystemTime::now() });
             }
         }
-        for sell in sell_orders {
-            if sell.quantity > 0.0 {
-                self.sell_orders.insert(sell.id, sell);
+    }
+
+    /// Trailing stops keep their distance to the best trade price seen since they were placed.
+    fn follow_trailing_stops(&mut self, price: Amount, events: &mut Vec<MatcherEvent>) {
+        let timestamp = SystemTime::now();
+        for order in self.stop_orders.iter() {
+            let PriceType::TrailingStop(offset) = order.price_type else {
+                continue;
+            };
+            let stop_price = trailing_stop_price(order.trade_type, offset, price);
+            let moved = match (order.trade_type, self.trailing_stop_prices.get(&order.id)) {
+                (_, None) => true,
+                (TradeType::Buy, Some(current)) => stop_price < *current,
+                (TradeType::Sell, Some(current)) => stop_price > *current,
+            };
+            if moved {
+                self.trailing_stop_prices.insert(order.id, stop_price);
+                events.push(MatcherEvent::TrailingStopMoved { order_id: order.id, stop_price, timestamp });
             }
         }
+    }
 
-        self.print_orders("After Matching");
-        matches
+    /// Side of the book an order of the given trade type takes liquidity from.
+    fn makers(&self, trade_type: TradeType) -> &BookSide {
+        match trade_type {
+            TradeType::Buy => &self.asks,
+            TradeType::Sell => &self.bids,
+        }
+    }
+
+    fn makers_mut(&mut self, trade_type: TradeType) -> &mut BookSide {
+        match trade_type {
+            TradeType::Buy => &mut self.asks,
+            TradeType::Sell => &mut self.bids,
+        }
+    }
+
+    /// Resting `ExecutionType::Full` orders are skipped by takers that can't fill them completely,
+    /// so they get another chance once the book has changed.
+    fn match_resting_full_orders(&mut self, events: &mut Vec<MatcherEvent>) {
+        let full_orders: Vec<(TradeType, u64)> = self.bids.iter().chain(self.asks.iter())
+            .filter(|order| order.execution_type == ExecutionType::Full && !order.post_only)
+            .map(|order| (order.trade_type, order.id))
+            .collect();
+
+        for (trade_type, order_id) in full_orders {
+            let side = match trade_type {
+                TradeType::Buy => &self.bids,
+                TradeType::Sell => &self.asks,
+            };
+            // Could have been filled by one of the previous orders
+            let Some(order) = side.get(order_id).copied() else {
+                continue;
+            };
+            if self.fillable_quantity(&order) >= order.quantity {
+                let order = self.remove_order(order_id).unwrap();
+                self.take_liquidity(order, events);
+            }
+        }
+    }
+
+    fn take_liquidity(&mut self, mut taker: Order, events: &mut Vec<MatcherEvent>) -> Order {
+        let self_trade_prevention = self.self_trade_prevention;
+        let base_precision = self.base_precision as u32;
+        let quote_precision = self.quote_precision as u32;
+
+        // All or none: either the whole quantity is filled now or the order just rests
+        if taker.execution_type == ExecutionType::Full && self.fillable_quantity(&taker) < taker.quantity {
+            return taker;
+        }
+
+        while has_open_quantity(&taker) {
+            let makers = self.makers_mut(taker.trade_type);
+            let Some((maker_id, price)) = find_maker(makers, &taker, base_precision) else {
+                break;
+            };
+            let maker = makers.get_mut(maker_id).unwrap();
+            if maker.account_id == taker.account_id && self_trade_prevention != SelfTradePrevention::Allow {
+                self.prevent_self_trade(&mut taker, maker_id, price, events);
+                continue;
+            }
+            let maker_oco_group_id = maker.oco_group_id;
+            let maker_quantity = fill_quantity(maker, price, base_precision);
+            if maker_quantity.is_zero() {
+                // A resting buy capped by its hold that can't pay for anything more
+                let maker = self.remove_order(maker_id).unwrap();
+                events.push(MatcherEvent::Cancelled { order_id: maker_id, quantity: maker.quantity, timestamp: SystemTime::now() });
+                continue;
+            }
+
+            // Calculate matched quantity
+            let matched_quantity = fill_quantity(&taker, price, base_precision).min(maker_quantity);
+            // Truncated, so fills never cost the buyer more than the funds held for the order
+            let amount = (matched_quantity * price).trunc_dp(quote_precision);
+            if matched_quantity.is_zero() || (spends_budget(&taker) && amount.is_zero()) {
+                // What is left of the budget doesn't pay for the smallest unit of the base asset
+                break;
+            }
+
+            // Update quantities
+            maker.quantity -= matched_quantity;
+            if maker.is_capped_by_hold() {
+                maker.held -= amount;
+            }
+            match taker.quote_quantity.as_mut() {
+                Some(budget) => *budget -= amount,
+                None => taker.quantity -= matched_quantity,
+            }
+            if taker.is_capped_by_hold() {
+                taker.held -= amount;
+            }
+            if !maker.quantity.is_positive() {
+                let maker = makers.remove(maker_id).unwrap();
+                self.replenish_iceberg(maker);
+            }
+
+            let (buy_order_id, sell_order_id) = match taker.trade_type {
+                TradeType::Buy => (taker.id, maker_id),
+                TradeType::Sell => (maker_id, taker.id),
+            };
+            self.last_price = Some(price);
+            events.push(MatcherEvent::Match(OrderMatch {
+                buy_order_id,
+                sell_order_id,
+                quantity: matched_quantity,
+                price,
+                amount,
+                aggressor: taker.trade_type,
+                timestamp: SystemTime::now(),
+            }));
+            self.follow_trailing_stops(price, events);
+            if let Some(oco_group_id) = taker.oco_group_id {
+                self.cancel_oco_group(oco_group_id, taker.id, events);
+            }
+            if let Some(oco_group_id) = maker_oco_group_id {
+                self.cancel_oco_group(oco_group_id, maker_id, events);
+            }
+        }
+        taker
+    }
+
+    /// Quantity of the taker the book can fill right now, taking the same steps as `take_liquidity`: price level
+    /// by price level, where an iceberg shows the next slice of its hidden reserve at the back of the level once
+    /// a slice is filled. Orders of the taker's own account are passed over if self-trade prevention cancels them,
+    /// any other prevention stops the taker there. Buys capped by their hold fill no more than the hold pays for.
+    fn fillable_quantity(&self, taker: &Order) -> Amount {
+        let base_precision = self.base_precision as u32;
+        let quote_precision = self.quote_precision as u32;
+        // What is left of the taker as it fills
+        let mut open = *taker;
+        let mut filled = Amount::ZERO;
+        // Hidden quantity left of the icebergs seen so far
+        let mut reserves: HashMap<u64, Amount> = HashMap::new();
+        let mut crossing = crossing_makers(self.makers(taker.trade_type), taker).peekable();
+        while let Some((first, price)) = crossing.next() {
+            let mut level = VecDeque::from([*first]);
+            while let Some((maker, _)) = crossing.next_if(|(maker, _)| maker.price_type == first.price_type) {
+                level.push_back(*maker);
+            }
+            while let Some(mut maker) = level.pop_front() {
+                if !has_open_quantity(&open) {
+                    return filled;
+                }
+                if !can_fill(&maker, fill_quantity(&open, price, base_precision), price, base_precision) {
+                    continue;
+                }
+                let reserve = reserves.get(&maker.id).copied()
+                    .unwrap_or_else(|| self.iceberg_reserves.get(&maker.id).copied().unwrap_or(Amount::ZERO));
+                if maker.account_id == taker.account_id {
+                    match self.self_trade_prevention {
+                        SelfTradePrevention::Allow => {}
+                        SelfTradePrevention::CancelOldest => continue,
+                        SelfTradePrevention::DecrementAndCancel if taker.quote_quantity.is_none() && open.quantity > maker.quantity + reserve => {
+                            open.quantity -= maker.quantity + reserve;
+                            continue;
+                        }
+                        SelfTradePrevention::CancelNewest | SelfTradePrevention::CancelBoth | SelfTradePrevention::DecrementAndCancel => return filled,
+                    }
+                }
+                let maker_quantity = fill_quantity(&maker, price, base_precision);
+                if maker_quantity.is_zero() {
+                    // Cancelled once reached
+                    continue;
+                }
+                let matched_quantity = fill_quantity(&open, price, base_precision).min(maker_quantity);
+                let amount = (matched_quantity * price).trunc_dp(quote_precision);
+                if matched_quantity.is_zero() || (spends_budget(&open) && amount.is_zero()) {
+                    return filled;
+                }
+                filled += matched_quantity;
+                maker.quantity -= matched_quantity;
+                if maker.is_capped_by_hold() {
+                    maker.held -= amount;
+                }
+                match open.quote_quantity.as_mut() {
+                    Some(budget) => *budget -= amount,
+                    None => open.quantity -= matched_quantity,
+                }
+                if open.is_capped_by_hold() {
+                    open.held -= amount;
+                }
+                if !maker.quantity.is_positive() && reserve.is_positive() {
+                    maker.quantity = reserve;
+                    reserves.insert(maker.id, show_slice(&mut maker).unwrap_or(Amount::ZERO));
+                    level.push_back(maker);
+                }
+            }
+        }
+        filled
     }
 
     fn print_orders(&self, title: &str) {
-        if !self.buy_orders.is_empty() || !self.sell_orders.is_empty() {
+        if !self.incoming_orders.is_empty() || !self.bids.is_empty() || !self.asks.is_empty() || !self.stop_orders.is_empty() {
             tracing::info!("{title}");
         }
-        for order in self.buy_orders.values() {
+        for order in self.incoming_orders.iter() {
+            tracing::info!("Incoming Order: {:?}", order);
+        }
+        for order in self.bids.iter() {
             tracing::info!("Buy Order: {:?}", order);
         }
-        for order in self.sell_orders.values() {
+        for order in self.asks.iter() {
             tracing::info!("Sell Order: {:?}", order);
         }
+        for order in self.stop_orders.iter() {
+            tracing::info!("Stop Order: {:?}", order);
+        }
+    }
+}
+
+fn is_expired(order: &Order, now: SystemTime) -> bool {
+    matches!(order.time_in_force, TimeInForce::GoodTillDate(deadline) if deadline <= now)
+}
+
+/// Quote quantity orders are open while there is budget left, buys capped by their hold while something is held.
+fn has_open_quantity(order: &Order) -> bool {
+    match order.quote_quantity {
+        Some(budget) => budget.is_positive(),
+        None => order.quantity.is_positive() && (!order.is_capped_by_hold() || order.held.is_positive()),
+    }
+}
+
+/// The order spends a budget, its quote quantity or the funds held for it, rather than filling a quantity.
+fn spends_budget(order: &Order) -> bool {
+    order.quote_quantity.is_some() || order.is_capped_by_hold()
+}
+
+/// Quantity the taker can still buy at the given price.
+fn open_quantity(taker: &Order, price: Amount) -> Amount {
+    match taker.quote_quantity {
+        Some(budget) => budget / price,
+        None => taker.quantity,
+    }
+}
+
+/// Quantity the order can trade at the given price in whole units of the base asset, a budget buys only as much as it pays for.
+fn fill_quantity(order: &Order, price: Amount, base_precision: u32) -> Amount {
+    let quantity = open_quantity(order, price).trunc_dp(base_precision);
+    if order.is_capped_by_hold() {
+        quantity.min((order.held / price).trunc_dp(base_precision))
+    } else {
+        quantity
+    }
+}
+
+/// Buy stops trigger when the price rises to the stop price, sell stops when it falls to it.
+fn is_stop_reached(order: &Order, trailing_stop_prices: &HashMap<u64, Amount>, last_price: Amount) -> bool {
+    let stop_price = match order.price_type {
+        PriceType::TrailingStop(_) => trailing_stop_prices.get(&order.id).copied(),
+        price_type => price_type.stop_price(),
+    };
+    match (order.trade_type, stop_price) {
+        (TradeType::Buy, Some(stop_price)) => last_price >= stop_price,
+        (TradeType::Sell, Some(stop_price)) => last_price <= stop_price,
+        (_, None) => false,
+    }
+}
+
+fn trailing_stop_price(trade_type: TradeType, offset: TrailingOffset, price: Amount) -> Amount {
+    match (trade_type, offset) {
+        (TradeType::Buy, TrailingOffset::Amount(amount)) => price + amount,
+        (TradeType::Buy, TrailingOffset::Percent(percent)) => price * (Amount::ONE + percent / Amount::from(100)),
+        (TradeType::Sell, TrailingOffset::Amount(amount)) => price - amount,
+        (TradeType::Sell, TrailingOffset::Percent(percent)) => price * (Amount::ONE - percent / Amount::from(100)),
+    }
+}
+
+/// Worst price a protected market order may trade at, deviations are taken from the reference price.
+fn protection_price(trade_type: TradeType, slippage: SlippageLimit, reference_price: Option<Amount>) -> Option<Amount> {
+    match (trade_type, slippage) {
+        (_, SlippageLimit::WorstPrice(price)) => Some(price),
+        (TradeType::Buy, SlippageLimit::MaxDeviation(percent)) => reference_price.map(|price| price * (Amount::ONE + percent / Amount::from(100))),
+        (TradeType::Sell, SlippageLimit::MaxDeviation(percent)) => reference_price.map(|price| price * (Amount::ONE - percent / Amount::from(100))),
+    }
+}
+
+/// Resting orders the taker crosses with, in price-time priority, together with the trade price.
+fn crossing_makers<'a>(makers: &'a BookSide, taker: &'a Order) -> impl Iterator<Item = (&'a Order, Amount)> + 'a {
+    makers.iter()
+        .filter(move |maker| taker.price_type != PriceType::Market || maker.price_type != PriceType::Market)
+        // Levels further down the book cross even less
+        .map_while(move |maker| execution_price(taker, maker).map(|price| (maker, price)))
+}
+
+/// A resting `ExecutionType::Full` order only trades if it's filled completely, and its hold pays for all of it
+/// if it's capped by its hold.
+fn can_fill(maker: &Order, quantity: Amount, price: Amount, base_precision: u32) -> bool {
+    maker.execution_type == ExecutionType::Partial
+        || (maker.quantity <= quantity && fill_quantity(maker, price, base_precision) >= maker.quantity)
+}
+
+/// First resting order in price-time priority the taker can trade with, and the trade price.
+fn find_maker(makers: &BookSide, taker: &Order, base_precision: u32) -> Option<(u64, Amount)> {
+    crossing_makers(makers, taker)
+        .find(|(maker, price)| can_fill(maker, fill_quantity(taker, *price, base_precision), *price, base_precision))
+        .map(|(maker, price)| (maker.id, price))
+}
+
+/// Cuts an iceberg order down to its display quantity, returns the hidden rest.
+fn show_slice(order: &mut Order) -> Option<Amount> {
+    let (PriceType::Limit(_), Some(display_quantity)) = (order.price_type, order.display_quantity) else {
+        return None;
+    };
+    if order.quantity <= display_quantity {
+        return None;
+    }
+    let reserve = order.quantity - display_quantity;
+    order.quantity = display_quantity;
+    Some(reserve)
+}
+
+/// Price at which a taker trades with a resting order, `None` if they don't cross.
+/// Trades happen at the resting (maker) order's price.
+fn execution_price(taker: &Order, maker: &Order) -> Option<Amount> {
+    match (taker.price_type, maker.price_type) {
+        (PriceType::Market, PriceType::Limit(maker_price)) => Some(maker_price),
+        // Resting market orders have no price of their own
+        (PriceType::Limit(taker_price), PriceType::Market) => Some(taker_price),
+        (PriceType::Limit(taker_price), PriceType::Limit(maker_price)) => {
+            let crosses = match taker.trade_type {
+                TradeType::Buy => taker_price >= maker_price,
+                TradeType::Sell => taker_price <= maker_price,
+            };
+            if crosses {
+                Some(maker_price)
+            } else {
+                None
+            }
+        }
+        // Stop orders only reach the book once triggered
+        _ => None,
     }
 }
 
 pub struct MatcherSystem {
-    order_queue:Arc<ArrayQueue<Order>>,
-    order_match_queue:Arc<ArrayQueue<OrderMatch>>,
+    /// Both queues are unbounded: a dropped command or event would leave funds held for good.
+    command_queue:Arc<SegQueue<MatcherCommand>>,
+    event_queue:Arc<SegQueue<MatcherEvent>>,
 }
 
 impl MatcherSystem {
-    pub fn start(crypto_currency_id: u64, currency_id: u64, core_id: CoreId) -> MatcherSystem {
-        let order_queue:Arc<ArrayQueue<Order>> = Arc::new(ArrayQueue::new(100));
-        let order_match_queue:Arc<ArrayQueue<OrderMatch>> = Arc::new(ArrayQueue::new(100));
-        let order_queue_clone = order_queue.clone();
-        let order_match_queue_clone = order_match_queue.clone();
+    /// Runs the matcher of a market on its own thread, pinned to `core_id`.
+    /// The precisions are the ones of the market's base and quote asset.
+    pub fn start(market_id: u64, base_precision: u8, quote_precision: u8, core_id: CoreId) -> MatcherSystem {
+        let command_queue:Arc<SegQueue<MatcherCommand>> = Arc::new(SegQueue::new());
+        let event_queue:Arc<SegQueue<MatcherEvent>> = Arc::new(SegQueue::new());
+        let command_queue_clone = command_queue.clone();
+        let event_queue_clone = event_queue.clone();
         let _match_system_thread_handle = std::thread::spawn(move || {
             let ok = core_affinity::set_for_current(core_id);
             if ok {
-                let mut matcher_system = OrderMatcher::new(crypto_currency_id, currency_id);
+                let mut matcher_system = OrderMatcher::new(market_id, base_precision, quote_precision);
                 loop {
-                    while let Some(order) = order_queue_clone.pop() {
-                        matcher_system.add_order(order);
+                    while let Some(command) = command_queue_clone.pop() {
+                        match command {
+                            MatcherCommand::AddOrder(order) => {
+                                matcher_system.add_order(order);
+                            }
+                            MatcherCommand::AddOcoOrders(orders) => {
+                                let (first, second) = *orders;
+                                matcher_system.add_oco_orders(first, second);
+                            }
+                            MatcherCommand::CancelOrder(order_id) => {
+                                event_queue_clone.push(matcher_system.cancel_order(order_id));
+                            }
+                            MatcherCommand::AmendOrder { order_id, price, quantity } => {
+                                event_queue_clone.push(matcher_system.amend_order(order_id, price, quantity));
+                            }
+                            MatcherCommand::SetSelfTradePrevention(self_trade_prevention) => {
+                                matcher_system.self_trade_prevention = self_trade_prevention;
+                            }
+                        }
                     };
                     let match_orders = span!(Level::TRACE, "match_orders");
                     let _ = match_orders.enter();
-                    let order_matches = matcher_system.match_orders();
+                    let events = matcher_system.match_orders();
                     drop(match_orders);
-                    for order_match in order_matches {
-                        let _ = order_match_queue_clone.push(order_match);
+                    for event in events {
+                        event_queue_clone.push(event);
                     }
                     // TODO remove for production to avoid busy loop
                     std::thread::sleep(std::time::Duration::from_secs(1)); // for testing
@@ -149,17 +815,35 @@ impl MatcherSystem {
 
 
         MatcherSystem {
-            order_queue,
-            order_match_queue,
+            command_queue,
+            event_queue,
         }
     }
 
     pub fn add_order(&self, order: Order) {
-        let _ = self.order_queue.push(order);
+        self.command_queue.push(MatcherCommand::AddOrder(order));
+    }
+
+    /// Adds both orders of a one-cancels-other group created by `OrderSystem::create_oco_orders`.
+    pub fn add_oco_orders(&self, first: Order, second: Order) {
+        self.command_queue.push(MatcherCommand::AddOcoOrders(Box::new((first, second))));
+    }
+
+    pub fn cancel_order(&self, order_id: u64) {
+        self.command_queue.push(MatcherCommand::CancelOrder(order_id));
+    }
+
+    pub fn set_self_trade_prevention(&self, self_trade_prevention: SelfTradePrevention) {
+        self.command_queue.push(MatcherCommand::SetSelfTradePrevention(self_trade_prevention));
+    }
+
+    /// `quantity` is the new open quantity of the order. The amendment has to be accepted by `OrderSystem::amend_order` first.
+    pub fn amend_order(&self, order_id: u64, price: Option<Amount>, quantity: Option<Amount>) {
+        self.command_queue.push(MatcherCommand::AmendOrder { order_id, price, quantity });
     }
 
-    pub fn get_order_match(&self) -> Option<OrderMatch> {
-        self.order_match_queue.pop()
+    pub fn get_event(&self) -> Option<MatcherEvent> {
+        self.event_queue.pop()
     }
 
 
@@ -1,18 +1,120 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::SystemTime;
 use bincode::{Decode, Encode};
 use crate::accounts::AccountSystem;
-use crate::assets::AssetSystem;
-use crate::matcher::OrderMatch;
-use crate::storage::StorageSystem;
+use crate::fees::{Fee, Liquidity};
+use crate::ledger::{EntryType, JournalReference, LedgerAccount};
+use crate::amount::Amount;
+use crate::assets::{Asset, AssetStatus, AssetSystem, Market, MarketStatus};
+use crate::matcher::{MatcherEvent, OrderMatch};
+use crate::storage::{Settlement, StorageSystem};
 
 #[derive(Debug, Clone, Copy, Encode, Decode)]
 pub struct Order {
     pub id: u64,
     pub account_id: u64,
+    pub market_id: u64,
     pub trade_type: TradeType,
     pub price_type: PriceType,
     pub execution_type: ExecutionType,
+    pub time_in_force: TimeInForce,
+    /// Asset bought or sold, taken from the market.
+    pub base_asset_id: u64,
+    /// Asset the price is expressed in, taken from the market.
+    pub quote_asset_id: u64,
+    pub quantity: Amount,
+    /// Market buys spend this much of the quote asset instead of buying `quantity`,
+    /// whatever is left once the book runs out is cancelled.
+    pub quote_quantity: Option<Amount>,
+    /// Market orders don't fill beyond this, the rest is cancelled or rests as a limit order
+    /// at the protection price depending on `time_in_force`.
+    pub slippage: Option<SlippageLimit>,
+    /// Iceberg orders only show this much of their quantity in the book at a time.
+    pub display_quantity: Option<Amount>,
+    /// Funds of the account still reserved for the order, see `held_asset_id`.
+    pub held: Amount,
+    /// Only ever rests in the book as a maker, rejected if it would trade on entry.
+    pub post_only: bool,
+    /// One-cancels-other group the order belongs to.
+    pub oco_group_id: Option<u64>,
+    pub timestamp: SystemTime,
+    pub status: OrderStatus,
+}
+
+impl Default for Order {
+    fn default() -> Self {
+        Order {
+            id: 0,
+            account_id: 0,
+            market_id: 0,
+            trade_type: TradeType::Buy,
+            price_type: PriceType::Market,
+            execution_type: ExecutionType::Partial,
+            time_in_force: TimeInForce::GoodTillCancel,
+            base_asset_id: 0,
+            quote_asset_id: 0,
+            quantity: Amount::ZERO,
+            quote_quantity: None,
+            slippage: None,
+            display_quantity: None,
+            held: Amount::ZERO,
+            post_only: false,
+            oco_group_id: None,
+            timestamp: SystemTime::now(),
+            status: OrderStatus::Open,
+        }
+    }
+}
+
+impl Order {
+    /// Asset the order spends: the quote asset for buys, the base asset for sells.
+    pub fn held_asset_id(&self) -> u64 {
+        match self.trade_type {
+            TradeType::Buy => self.quote_asset_id,
+            TradeType::Sell => self.base_asset_id,
+        }
+    }
+
+    /// Buys whose worst case cost isn't known up front: market buys (and stops that become one) without a worst price
+    /// or quote quantity, and buys protected by a maximum deviation. They hold the available balance when created
+    /// and never spend more than what is held for them.
+    pub fn is_capped_by_hold(&self) -> bool {
+        if self.trade_type != TradeType::Buy || self.quote_quantity.is_some() {
+            return false;
+        }
+        match self.slippage {
+            Some(SlippageLimit::MaxDeviation(_)) => true,
+            Some(SlippageLimit::WorstPrice(_)) => false,
+            None => self.price_type.limit_price().is_none(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Encode, Decode)]
+pub struct OrderHistory {
+    pub id: u64,
+    pub order_id: u64,
+    pub history_type: OrderHistoryType,
+    pub oco_group_id: Option<u64>,
+    pub quantity: Amount,
+    /// Trade price of a fill or new limit price of an amendment.
+    pub price: Option<Amount>,
+    /// Quote asset paid for a fill.
+    pub amount: Option<Amount>,
+    pub timestamp: SystemTime,
+    pub status: OrderStatus,
+}
+
+/// Order as stored before markets and `Amount`, only read to migrate old databases.
+/// The crypto currency was bought and sold for the currency.
+#[derive(Debug, Encode, Decode)]
+pub(crate) struct LegacyOrder {
+    pub id: u64,
+    pub account_id: u64,
+    pub trade_type: TradeType,
+    pub price_type: LegacyPriceType,
+    pub execution_type: ExecutionType,
     pub crypto_currency_id: u64,
     pub currency_id: u64,
     pub quantity: f64,
@@ -20,8 +122,15 @@ pub struct Order {
     pub status: OrderStatus,
 }
 
-#[derive(Debug, Clone, Copy, Encode, Decode)]
-pub struct OrderHistory {
+#[derive(Debug, Encode, Decode)]
+pub(crate) enum LegacyPriceType {
+    Market,
+    Limit(f64),
+}
+
+/// Fill of a `LegacyOrder`, only read to migrate old databases.
+#[derive(Debug, Encode, Decode)]
+pub(crate) struct LegacyOrderHistory {
     pub id: u64,
     pub order_id: u64,
     pub quantity: f64,
@@ -29,12 +138,33 @@ pub struct OrderHistory {
     pub status: OrderStatus,
 }
 
+/// Two linked orders, once one of them trades the other is cancelled.
 #[derive(Debug, Clone, Copy, Encode, Decode)]
+pub struct OcoGroup {
+    pub id: u64,
+    pub order_ids: [u64; 2],
+    pub timestamp: SystemTime,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Encode, Decode)]
 pub enum OrderStatus {
     Open,
     Closed,
     PartiallyFilled,
     Cancelled,
+    Expired,
+    Rejected,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Encode, Decode)]
+pub enum OrderHistoryType {
+    Fill,
+    Cancel,
+    Expire,
+    Reject,
+    Amend,
+    Trigger,
+    SlippageProtect,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Encode, Decode)]
@@ -46,18 +176,134 @@ pub enum TradeType {
 #[derive(Debug, PartialEq,Clone, Copy, Encode, Decode)]
 pub enum PriceType {
     Market,
-    Limit(f64),
+    Limit(Amount),
+    /// Becomes a market order once the last trade price reaches the stop price.
+    StopMarket(Amount),
+    /// Becomes a limit order once the last trade price reaches the stop price.
+    StopLimit { stop_price: Amount, limit_price: Amount },
+    /// Stop price follows the best trade price at the given distance, becomes a market order once reached.
+    TrailingStop(TrailingOffset),
 }
 
-#[derive(Debug, Clone, Copy, Encode, Decode)]
+#[derive(Debug, PartialEq, Clone, Copy, Encode, Decode)]
+pub enum TrailingOffset {
+    Amount(Amount),
+    Percent(Amount),
+}
+
+/// Why an order was not accepted.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OrderError {
+    MarketNotFound { market_id: u64 },
+    OrderNotFound { order_id: u64 },
+    /// Only open or partially filled orders can be amended.
+    OrderNotOpen { order_id: u64, status: OrderStatus },
+    /// Only limit orders can be given a new price.
+    PriceNotAmendable { price_type: PriceType },
+    MarketNotTrading { market_id: u64, status: MarketStatus },
+    AssetNotActive { asset_id: u64, status: AssetStatus },
+    /// A quantity has more decimal places than its asset.
+    Precision { value: Amount, asset_id: u64, precision: u8 },
+    TickSize { price: Amount, tick_size: Amount },
+    LotSize { quantity: Amount, lot_size: Amount },
+    MinQuantity { quantity: Amount, min_quantity: Amount },
+    MaxQuantity { quantity: Amount, max_quantity: Amount },
+    MinNotional { notional: Amount, min_notional: Amount },
+    NonPositiveQuantity { quantity: Amount },
+    NonPositiveQuoteQuantity { quote_quantity: Amount },
+    /// A limit, stop or worst price that is zero or negative.
+    NonPositivePrice { price: Amount },
+    NonPositiveTrailingOffset { offset: TrailingOffset },
+    /// Only market buys can be given a quote quantity.
+    QuoteQuantity { trade_type: TradeType, price_type: PriceType },
+    /// Both orders of a one-cancels-other group have to be in the same market.
+    OcoMarkets { first_market_id: u64, second_market_id: u64 },
+    /// The visible slice of an iceberg order has to be positive and at most its quantity.
+    DisplayQuantity { display_quantity: Amount, quantity: Amount },
+    /// The available balance doesn't cover what the order can cost at worst.
+    InsufficientFunds { asset_id: u64, required: Amount, available: Amount },
+}
+
+impl std::fmt::Display for OrderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderError::MarketNotFound { market_id } => write!(f, "market {market_id} not found"),
+            OrderError::OrderNotFound { order_id } => write!(f, "order {order_id} not found"),
+            OrderError::OrderNotOpen { order_id, status } => write!(f, "order {order_id} is not open ({status:?})"),
+            OrderError::PriceNotAmendable { price_type } => write!(f, "the price of a {price_type:?} order can't be amended"),
+            OrderError::MarketNotTrading { market_id, status } => write!(f, "market {market_id} is not trading ({status:?})"),
+            OrderError::AssetNotActive { asset_id, status } => write!(f, "asset {asset_id} is not active ({status:?})"),
+            OrderError::Precision { value, asset_id, precision } => write!(f, "{value} has more than the {precision} decimal places of asset {asset_id}"),
+            OrderError::TickSize { price, tick_size } => write!(f, "price {price} is not a multiple of the tick size {tick_size}"),
+            OrderError::LotSize { quantity, lot_size } => write!(f, "quantity {quantity} is not a multiple of the lot size {lot_size}"),
+            OrderError::MinQuantity { quantity, min_quantity } => write!(f, "quantity {quantity} is below the minimum {min_quantity}"),
+            OrderError::MaxQuantity { quantity, max_quantity } => write!(f, "quantity {quantity} is above the maximum {max_quantity}"),
+            OrderError::MinNotional { notional, min_notional } => write!(f, "notional {notional} is below the minimum {min_notional}"),
+            OrderError::NonPositiveQuantity { quantity } => write!(f, "quantity {quantity} is not positive"),
+            OrderError::NonPositiveQuoteQuantity { quote_quantity } => write!(f, "quote quantity {quote_quantity} is not positive"),
+            OrderError::NonPositivePrice { price } => write!(f, "price {price} is not positive"),
+            OrderError::NonPositiveTrailingOffset { offset } => write!(f, "trailing offset {offset:?} is not positive"),
+            OrderError::QuoteQuantity { trade_type, price_type } => write!(f, "quote quantity on a {trade_type:?} order with price {price_type:?}, only market buys can have one"),
+            OrderError::OcoMarkets { first_market_id, second_market_id } => write!(f, "one-cancels-other orders in different markets {first_market_id} and {second_market_id}"),
+            OrderError::DisplayQuantity { display_quantity, quantity } => write!(f, "display quantity {display_quantity} is not between 0 and the quantity {quantity}"),
+            OrderError::InsufficientFunds { asset_id, required, available } => write!(f, "{required} of asset {asset_id} required, {available} available"),
+        }
+    }
+}
+
+impl std::error::Error for OrderError {}
+
+/// Slippage protection of a market order.
+#[derive(Debug, PartialEq, Clone, Copy, Encode, Decode)]
+pub enum SlippageLimit {
+    /// Percentage from the best opposite price when the order arrives (the last trade price if that side is empty).
+    MaxDeviation(Amount),
+    WorstPrice(Amount),
+}
+
+impl PriceType {
+    pub fn limit_price(&self) -> Option<Amount> {
+        match self {
+            PriceType::Limit(limit_price) | PriceType::StopLimit { limit_price, .. } => Some(*limit_price),
+            PriceType::Market | PriceType::StopMarket(_) | PriceType::TrailingStop(_) => None,
+        }
+    }
+
+    pub fn stop_price(&self) -> Option<Amount> {
+        match self {
+            PriceType::StopMarket(stop_price) | PriceType::StopLimit { stop_price, .. } => Some(*stop_price),
+            PriceType::Market | PriceType::Limit(_) | PriceType::TrailingStop(_) => None,
+        }
+    }
+
+    pub fn is_stop(&self) -> bool {
+        matches!(self, PriceType::StopMarket(_) | PriceType::StopLimit { .. } | PriceType::TrailingStop(_))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Encode, Decode)]
 pub enum ExecutionType {
     Full,
     Partial,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy, Encode, Decode)]
+pub enum TimeInForce {
+    GoodTillCancel,
+    /// Whatever is not filled right away is cancelled.
+    ImmediateOrCancel,
+    /// Filled completely right away or rejected.
+    FillOrKill,
+    /// Expires at the given time.
+    GoodTillDate(SystemTime),
+}
+
 pub struct OrderSystem {
     pub order_last_id: u64,
     pub order_history_id: u64,
+    pub oco_group_last_id: u64,
+    /// Latest stop price reported by the matcher for trailing stop orders.
+    pub trailing_stop_prices: HashMap<u64, Amount>,
     pub storage_system: Arc<StorageSystem>,
     pub assets_system: Arc<AssetSystem>,
 }
@@ -78,69 +324,497 @@ impl OrderSystem {
                 order_history_id = order_history.id;
             }
         }
+        let mut oco_group_last_id = 0;
+        match storage_system.get_last_oco_group() {
+            None => {}
+            Some(oco_group) => {
+                oco_group_last_id = oco_group.id;
+            }
+        }
 
 
         OrderSystem {
             order_last_id,
             order_history_id,
+            oco_group_last_id,
+            trailing_stop_prices: HashMap::new(),
             storage_system,
             assets_system,
         }
     }
 
-    pub fn create_order(&mut self, mut order: Order) -> Order {
+    /// Stores the order once it passes the trading rules of its market and holds the funds it can cost at worst.
+    /// Base and quote of the order are taken from the market.
+    pub fn create_order(&mut self, mut order: Order, accounts_system: &mut AccountSystem) -> Result<Order, OrderError> {
+        let market = self.validate_order(&order)?;
+        order.base_asset_id = market.base_asset_id;
+        order.quote_asset_id = market.quote_asset_id;
+        order.held = self.required_hold(&order, accounts_system)?;
+        // The order and its hold are stored together
+        let mut settlement = Settlement::default();
+        let order = self.stage_order(order, accounts_system, &mut settlement);
+        self.storage_system.settle(&settlement);
+        Ok(order)
+    }
+
+    /// Adds the order with a new id and its hold to the settlement.
+    fn stage_order(&mut self, mut order: Order, accounts_system: &mut AccountSystem, settlement: &mut Settlement) -> Order {
         self.order_last_id += 1;
         order.id = self.order_last_id;
-        self.storage_system.add_order(&order);
+        settlement.orders.push(order);
+        accounts_system.stage_hold_change(settlement, order.account_id, order.held_asset_id(), order.held);
         order
     }
 
-    pub fn create_order_history(&mut self, order_match: &OrderMatch,  accounts_system: &mut AccountSystem) {
-        {
-
-            let mut buy_order: Order = self.storage_system.get_order(order_match.buy_order_id).unwrap();
-            let quantity = self.storage_system.get_order_histories_by_order_id(order_match.buy_order_id).iter().fold(0.0, |acc, x| acc + x.quantity);
-            let status = if (quantity + order_match.quantity) == buy_order.quantity {
-                OrderStatus::Closed
-            } else {
-                OrderStatus::PartiallyFilled
-            };
-            buy_order.status = status;
-
-            self.order_history_id += 1;
-            let order_history = OrderHistory {
-                id: self.order_history_id,
-                order_id: order_match.buy_order_id,
-                quantity: order_match.quantity,
-                timestamp: order_match.timestamp,
-                status: status,
-            };
-            self.storage_system.add_order_history(&order_history);
-            accounts_system.add_currency_to_account(buy_order.account_id, buy_order.currency_id, -(order_match.quantity as f64 * order_match.price));
-            accounts_system.add_crypto_currency_to_account(buy_order.account_id, buy_order.crypto_currency_id, order_match.quantity as f64);
-        }
-
-        let mut sell_order: Order = self.storage_system.get_order(order_match.sell_order_id).unwrap();
-        let quantity = self.storage_system.get_order_histories_by_order_id(order_match.sell_order_id).iter().fold(0.0, |acc, x| acc + x.quantity);
-        let status = if (quantity + order_match.quantity) == sell_order.quantity {
+    /// Funds the order has to hold: the quantity for sells, the quote quantity or quantity times
+    /// the limit (or worst) price for buys. Buys capped by their hold take the whole available balance,
+    /// the matcher doesn't let them spend more and what is left is released once they are closed.
+    fn required_hold(&self, order: &Order, accounts_system: &AccountSystem) -> Result<Amount, OrderError> {
+        let asset_id = order.held_asset_id();
+        let available = accounts_system.get_available_balance(order.account_id, asset_id);
+        let Some(required) = self.worst_case_cost(order, order.quantity) else {
+            if !available.is_positive() {
+                // Nothing held would buy nothing
+                let precision = self.assets_system.get_asset(asset_id).unwrap().precision;
+                return Err(OrderError::InsufficientFunds { asset_id, required: Amount::new(1, precision as u32), available });
+            }
+            return Ok(available);
+        };
+        if required > available {
+            return Err(OrderError::InsufficientFunds { asset_id, required, available });
+        }
+        Ok(required)
+    }
+
+    /// Most buying or selling `quantity` with the order can cost, `None` for buys capped by their hold.
+    fn worst_case_cost(&self, order: &Order, quantity: Amount) -> Option<Amount> {
+        if order.is_capped_by_hold() {
+            return None;
+        }
+        let price = match (order.trade_type, order.quote_quantity) {
+            (TradeType::Sell, _) => return Some(quantity),
+            (TradeType::Buy, Some(quote_quantity)) => return Some(quote_quantity),
+            (TradeType::Buy, None) => match (order.price_type.limit_price(), order.slippage) {
+                (Some(price), _) | (None, Some(SlippageLimit::WorstPrice(price))) => price,
+                _ => return None,
+            },
+        };
+        let precision = self.assets_system.get_asset(order.quote_asset_id).unwrap().precision;
+        Some((quantity * price).round_up_dp(precision as u32))
+    }
+
+    /// Checks that the order's market exists, is trading and that the order passes its trading rules.
+    pub fn validate_order(&self, order: &Order) -> Result<Market, OrderError> {
+        let Some(market) = self.assets_system.get_market(order.market_id) else {
+            return Err(OrderError::MarketNotFound { market_id: order.market_id });
+        };
+        if market.status != MarketStatus::Trading {
+            return Err(OrderError::MarketNotTrading { market_id: market.id, status: market.status });
+        }
+        for asset_id in [market.base_asset_id, market.quote_asset_id] {
+            match self.assets_system.get_asset(asset_id) {
+                Some(asset) if asset.status != AssetStatus::Active => {
+                    return Err(OrderError::AssetNotActive { asset_id, status: asset.status });
+                }
+                _ => {}
+            }
+        }
+        // Negative quantities or prices would hold negative funds
+        match order.quote_quantity {
+            Some(quote_quantity) if !quote_quantity.is_positive() => return Err(OrderError::NonPositiveQuoteQuantity { quote_quantity }),
+            Some(_) => {}
+            None if !order.quantity.is_positive() => return Err(OrderError::NonPositiveQuantity { quantity: order.quantity }),
+            None => {}
+        }
+        let worst_price = match order.slippage {
+            Some(SlippageLimit::WorstPrice(price)) => Some(price),
+            _ => None,
+        };
+        for price in [order.price_type.limit_price(), order.price_type.stop_price(), worst_price].into_iter().flatten() {
+            if !price.is_positive() {
+                return Err(OrderError::NonPositivePrice { price });
+            }
+        }
+        if let PriceType::TrailingStop(offset) = order.price_type {
+            let (TrailingOffset::Amount(value) | TrailingOffset::Percent(value)) = offset;
+            if !value.is_positive() {
+                return Err(OrderError::NonPositiveTrailingOffset { offset });
+            }
+        }
+        if order.quote_quantity.is_some() && (order.trade_type, order.price_type) != (TradeType::Buy, PriceType::Market) {
+            return Err(OrderError::QuoteQuantity { trade_type: order.trade_type, price_type: order.price_type });
+        }
+        if let Some(display_quantity) = order.display_quantity {
+            if !display_quantity.is_positive() || display_quantity > order.quantity {
+                return Err(OrderError::DisplayQuantity { display_quantity, quantity: order.quantity });
+            }
+        }
+        // Prices only have to fit the tick size, they can be finer than the quote asset
+        if let Some(base_asset) = self.assets_system.get_asset(market.base_asset_id) {
+            for quantity in [Some(order.quantity), order.display_quantity].into_iter().flatten() {
+                check_precision(quantity, &base_asset)?;
+            }
+        }
+        if let (Some(quote_asset), Some(quote_quantity)) = (self.assets_system.get_asset(market.quote_asset_id), order.quote_quantity) {
+            check_precision(quote_quantity, &quote_asset)?;
+        }
+        market.trading_rules.validate(order)?;
+        Ok(market)
+    }
+
+    /// Creates two orders of the same market linked into a one-cancels-other group, to be passed to `MatcherSystem::add_oco_orders`.
+    /// Neither order is created if one of them is not valid. Only one of the orders can trade, so orders holding
+    /// the same funds share one hold of the larger amount. Both orders, their holds and the group are stored together.
+    pub fn create_oco_orders(&mut self, mut first: Order, mut second: Order, accounts_system: &mut AccountSystem) -> Result<(Order, Order), OrderError> {
+        if first.market_id != second.market_id {
+            return Err(OrderError::OcoMarkets { first_market_id: first.market_id, second_market_id: second.market_id });
+        }
+        for order in [&mut first, &mut second] {
+            let market = self.validate_order(order)?;
+            order.base_asset_id = market.base_asset_id;
+            order.quote_asset_id = market.quote_asset_id;
+        }
+        first.held = self.required_hold(&first, accounts_system)?;
+        second.held = self.required_hold(&second, accounts_system)?;
+        self.oco_group_last_id += 1;
+        first.oco_group_id = Some(self.oco_group_last_id);
+        second.oco_group_id = Some(self.oco_group_last_id);
+        let mut settlement = Settlement::default();
+        let first = self.stage_order(first, accounts_system, &mut settlement);
+        let second = self.stage_order(second, accounts_system, &mut settlement);
+        if shares_hold(&first, &second) {
+            accounts_system.stage_hold_change(&mut settlement, first.account_id, first.held_asset_id(), -first.held.min(second.held));
+        }
+        settlement.oco_groups.push(OcoGroup {
+            id: self.oco_group_last_id,
+            order_ids: [first.id, second.id],
+            timestamp: SystemTime::now(),
+        });
+        self.storage_system.settle(&settlement);
+        Ok((first, second))
+    }
+
+    pub fn get_oco_group(&self, oco_group_id: u64) -> Option<OcoGroup> {
+        self.storage_system.get_oco_group(oco_group_id)
+    }
+
+    /// The other order of the one-cancels-other group if it shares the hold with this one, as staged so far.
+    fn hold_sharing_order(&self, order: &Order, settlement: &Settlement) -> Option<Order> {
+        let oco_group = self.storage_system.get_oco_group(order.oco_group_id?)?;
+        let other_order_id = oco_group.order_ids.into_iter().find(|order_id| *order_id != order.id)?;
+        let other_order = match settlement.orders.iter().rev().find(|staged| staged.id == other_order_id) {
+            Some(staged) => *staged,
+            None => self.storage_system.get_order(other_order_id)?,
+        };
+        shares_hold(order, &other_order).then_some(other_order)
+    }
+
+    /// Changes the funds held for the order. The account holds only the larger hold of two orders sharing it.
+    fn set_hold(&mut self, order: &mut Order, held: Amount, accounts_system: &mut AccountSystem, settlement: &mut Settlement) {
+        let shared = self.hold_sharing_order(order, settlement).map_or(Amount::ZERO, |other_order| other_order.held);
+        let held_change = held.max(shared) - order.held.max(shared);
+        if !held_change.is_zero() {
+            accounts_system.stage_hold_change(settlement, order.account_id, order.held_asset_id(), held_change);
+        }
+        order.held = held;
+    }
+
+    /// Stores what the matcher reported, every event is written in one storage transaction.
+    pub fn process_event(&mut self, event: &MatcherEvent, accounts_system: &mut AccountSystem) {
+        let mut settlement = Settlement::default();
+        match event {
+            MatcherEvent::Match(order_match) => {
+                self.create_order_history(order_match, accounts_system, &mut settlement);
+            }
+            MatcherEvent::Cancelled { order_id, quantity, timestamp } => {
+                self.close_order(*order_id, OrderStatus::Cancelled, *quantity, *timestamp, accounts_system, &mut settlement);
+            }
+            MatcherEvent::Expired { order_id, quantity, timestamp } => {
+                self.close_order(*order_id, OrderStatus::Expired, *quantity, *timestamp, accounts_system, &mut settlement);
+            }
+            MatcherEvent::Rejected { order_id, timestamp, .. } => {
+                let quantity = self.storage_system.get_order(*order_id).unwrap().quantity;
+                self.close_order(*order_id, OrderStatus::Rejected, quantity, *timestamp, accounts_system, &mut settlement);
+            }
+            MatcherEvent::Amended { order_id, price_type, quantity, timestamp, .. } => {
+                self.apply_amendment(*order_id, *price_type, *quantity, *timestamp, accounts_system, &mut settlement);
+            }
+            MatcherEvent::SelfTradePrevented { taker_order_id, maker_order_id, quantity, .. } => {
+                tracing::warn!("Self-trade prevented: Taker Order Id: {taker_order_id} Maker Order Id: {maker_order_id} Quantity: {quantity}");
+            }
+            MatcherEvent::TrailingStopMoved { order_id, stop_price, .. } => {
+                self.trailing_stop_prices.insert(*order_id, *stop_price);
+            }
+            MatcherEvent::Triggered { order_id, price_type, quantity, timestamp } => {
+                let order: Order = self.storage_system.get_order(*order_id).unwrap();
+                settlement.order_histories.push(self.new_order_history(&order, OrderHistoryType::Trigger, *quantity, price_type.limit_price(), None, *timestamp));
+            }
+            MatcherEvent::SlippageProtected { order_id, price, quantity, timestamp } => {
+                let mut order: Order = self.storage_system.get_order(*order_id).unwrap();
+                order.price_type = PriceType::Limit(*price);
+                // The rest costs at most the protection price now, the matcher trims the hold the same way
+                if order.is_capped_by_hold() {
+                    let precision = self.assets_system.get_asset(order.quote_asset_id).unwrap().precision;
+                    let required = (*quantity * *price).round_up_dp(precision as u32);
+                    if required < order.held {
+                        self.set_hold(&mut order, required, accounts_system, &mut settlement);
+                    }
+                }
+                settlement.order_histories.push(self.new_order_history(&order, OrderHistoryType::SlippageProtect, *quantity, Some(*price), None, *timestamp));
+                settlement.orders.push(order);
+            }
+            MatcherEvent::AmendRejected { order_id, .. } => {
+                tracing::warn!("Order amendment rejected: {order_id}");
+                // Funds held for the amendment aren't needed
+                let mut order: Order = self.storage_system.get_order(*order_id).unwrap();
+                self.trim_hold(&mut order, accounts_system, &mut settlement);
+                settlement.orders.push(order);
+            }
+            MatcherEvent::OrderNotFound { order_id, .. } => {
+                tracing::warn!("Order not found in matcher: {order_id}");
+            }
+        }
+        self.storage_system.settle(&settlement);
+    }
+
+    /// Stages both legs of the trade: the fills of both orders, the journal entry moving the assets between the accounts
+    /// and the fees of both sides.
+    pub fn create_order_history(&mut self, order_match: &OrderMatch,  accounts_system: &mut AccountSystem, settlement: &mut Settlement) {
+        let buy_order = self.fill_order(order_match.buy_order_id, order_match, settlement);
+        let sell_order = self.fill_order(order_match.sell_order_id, order_match, settlement);
+        let reference = JournalReference::Match { buy_order_id: buy_order.id, sell_order_id: sell_order.id };
+        accounts_system.stage_journal_entry(settlement, EntryType::Trade, reference, &[
+            (LedgerAccount::Account(buy_order.account_id), buy_order.quote_asset_id, -order_match.amount),
+            (LedgerAccount::Account(sell_order.account_id), sell_order.quote_asset_id, order_match.amount),
+            (LedgerAccount::Account(buy_order.account_id), buy_order.base_asset_id, order_match.quantity),
+            (LedgerAccount::Account(sell_order.account_id), sell_order.base_asset_id, -order_match.quantity),
+        ]);
+        accounts_system.fee_system.update_fee_tiers(order_match.timestamp);
+        let (buy_liquidity, sell_liquidity) = match order_match.aggressor {
+            TradeType::Buy => (Liquidity::Taker, Liquidity::Maker),
+            TradeType::Sell => (Liquidity::Maker, Liquidity::Taker),
+        };
+        self.charge_fee(&buy_order, buy_liquidity, order_match, accounts_system, settlement);
+        self.charge_fee(&sell_order, sell_liquidity, order_match, accounts_system, settlement);
+        self.spend_hold(buy_order, order_match.amount, accounts_system, settlement);
+        self.spend_hold(sell_order, order_match.quantity, accounts_system, settlement);
+    }
+
+    /// Moves the fee for the fill from what the order received to the fee account of the market,
+    /// rounded to the precision of the received asset. The rate depends on the fee tier of the account.
+    /// Negative rates pay a rebate in the received asset out of the fee account, which has to hold it:
+    /// fees are collected in the asset each side receives, so fees taken from the other side don't fund it.
+    /// A rebate the fee account can't cover is cut down to its available balance.
+    fn charge_fee(&mut self, order: &Order, liquidity: Liquidity, order_match: &OrderMatch, accounts_system: &mut AccountSystem, settlement: &mut Settlement) {
+        let Some(fee_schedule) = accounts_system.fee_system.get_fee_schedule(order.market_id) else {
+            return;
+        };
+        let (asset_id, received) = match order.trade_type {
+            TradeType::Buy => (order.base_asset_id, order_match.quantity),
+            TradeType::Sell => (order.quote_asset_id, order_match.amount),
+        };
+        let precision = self.assets_system.get_asset(asset_id).unwrap().precision as u32;
+        let tier = accounts_system.get_fee_tier(order.account_id, order.market_id);
+        let rate = fee_schedule.rate(liquidity, tier);
+        let mut amount = (received * rate).round_dp(precision);
+        if amount.is_negative() {
+            let available = accounts_system.get_staged_available_balance(settlement, fee_schedule.fee_account_id, asset_id).max(Amount::ZERO);
+            if -amount > available {
+                tracing::warn!("Fee account {} holds {available} of asset {asset_id}, rebate of {} for order {} cut down", fee_schedule.fee_account_id, -amount, order.id);
+                amount = -available;
+            }
+        }
+        if amount.is_zero() {
+            return;
+        }
+        let reference = JournalReference::Match { buy_order_id: order_match.buy_order_id, sell_order_id: order_match.sell_order_id };
+        let journal_entry_id = accounts_system.stage_journal_entry(settlement, EntryType::Fee, reference, &[
+            (LedgerAccount::Account(order.account_id), asset_id, -amount),
+            (LedgerAccount::Account(fee_schedule.fee_account_id), asset_id, amount),
+        ]);
+        let fee = Fee {
+            id: 0,
+            order_id: order.id,
+            account_id: order.account_id,
+            market_id: order.market_id,
+            asset_id,
+            liquidity,
+            rate,
+            amount,
+            journal_entry_id,
+            timestamp: order_match.timestamp,
+        };
+        accounts_system.fee_system.stage_fee(settlement, fee);
+    }
+
+    /// Pays for a fill out of the funds held for the order, the rest of the hold is released once the order is closed.
+    fn spend_hold(&mut self, mut order: Order, amount: Amount, accounts_system: &mut AccountSystem, settlement: &mut Settlement) {
+        let from_hold = amount.min(order.held);
+        accounts_system.stage_hold_change(settlement, order.account_id, order.held_asset_id(), -from_hold);
+        // What was spent is gone for the other order sharing the hold as well
+        if let Some(mut other_order) = self.hold_sharing_order(&order, settlement) {
+            other_order.held = other_order.held.min(order.held.max(other_order.held) - from_hold);
+            settlement.orders.push(other_order);
+        }
+        order.held -= from_hold;
+        if order.status == OrderStatus::Closed {
+            self.release_hold(&mut order, accounts_system, settlement);
+        }
+        settlement.orders.push(order);
+    }
+
+    fn release_hold(&mut self, order: &mut Order, accounts_system: &mut AccountSystem, settlement: &mut Settlement) {
+        if order.held.is_positive() {
+            self.set_hold(order, Amount::ZERO, accounts_system, settlement);
+        }
+    }
+
+    fn fill_order(&mut self, order_id: u64, order_match: &OrderMatch, settlement: &mut Settlement) -> Order {
+        let mut order: Order = self.storage_system.get_order(order_id).unwrap();
+        let filled = match order.quote_quantity {
+            // Budget is spent in the same steps as in the matcher, so the last fill leaves exactly nothing
+            Some(quote_quantity) => !(self.get_remaining_budget(order_id, quote_quantity) - order_match.amount).is_positive(),
+            // Fills of all iceberg slices add up to the total quantity
+            None => (self.get_filled_quantity(order_id) + order_match.quantity) >= order.quantity,
+        };
+        let status = if filled {
             OrderStatus::Closed
         } else {
             OrderStatus::PartiallyFilled
         };
-        sell_order.status = status;
+        order.status = status;
 
-        self.order_history_id += 1;
-        let order_history = OrderHistory {
-            id: self.order_history_id,
-            order_id: order_match.sell_order_id,
-            quantity: order_match.quantity,
-            timestamp: order_match.timestamp,
-            status: status,
+        settlement.order_histories.push(self.new_order_history(&order, OrderHistoryType::Fill, order_match.quantity, Some(order_match.price), Some(order_match.amount), order_match.timestamp));
+        order
+    }
+
+    /// Stop price of a stop order, for trailing stops the level they trail at right now.
+    pub fn get_stop_price(&self, order_id: u64) -> Option<Amount> {
+        let order = self.storage_system.get_order(order_id)?;
+        match order.price_type {
+            PriceType::TrailingStop(_) => self.trailing_stop_prices.get(&order_id).copied(),
+            price_type => price_type.stop_price(),
+        }
+    }
+
+    pub fn get_filled_quantity(&self, order_id: u64) -> Amount {
+        self.storage_system.get_order_histories_by_order_id(order_id).iter()
+            .filter(|order_history| order_history.history_type == OrderHistoryType::Fill)
+            .fold(Amount::ZERO, |acc, x| acc + x.quantity)
+    }
+
+    /// Part of the quote quantity not spent on fills yet.
+    pub fn get_remaining_budget(&self, order_id: u64, quote_quantity: Amount) -> Amount {
+        self.storage_system.get_order_histories_by_order_id(order_id).iter()
+            .filter_map(|order_history| order_history.amount)
+            .fold(quote_quantity, |acc, x| acc - x)
+    }
+
+    /// Marks the order as no longer open, `quantity` is the open quantity taken off the book.
+    fn close_order(&mut self, order_id: u64, status: OrderStatus, quantity: Amount, timestamp: SystemTime, accounts_system: &mut AccountSystem, settlement: &mut Settlement) {
+        let mut order: Order = self.storage_system.get_order(order_id).unwrap();
+        order.status = status;
+        let history_type = match status {
+            OrderStatus::Expired => OrderHistoryType::Expire,
+            OrderStatus::Rejected => OrderHistoryType::Reject,
+            _ => OrderHistoryType::Cancel,
+        };
+        self.release_hold(&mut order, accounts_system, settlement);
+        settlement.order_histories.push(self.new_order_history(&order, history_type, quantity, None, None, timestamp));
+        settlement.orders.push(order);
+    }
+
+    /// Checks the amendment of an open order and holds what the amended order can cost at worst, to be passed to
+    /// `MatcherSystem::amend_order` once accepted. `quantity` is the new open quantity, only limit orders can get a new price.
+    /// Returns the order as it is once amended, funds it no longer needs are released when the matcher reports the amendment.
+    pub fn amend_order(&mut self, order_id: u64, price: Option<Amount>, quantity: Option<Amount>, accounts_system: &mut AccountSystem) -> Result<Order, OrderError> {
+        let Some(mut order) = self.storage_system.get_order(order_id) else {
+            return Err(OrderError::OrderNotFound { order_id });
+        };
+        if !matches!(order.status, OrderStatus::Open | OrderStatus::PartiallyFilled) {
+            return Err(OrderError::OrderNotOpen { order_id, status: order.status });
+        }
+        let mut amended = order;
+        amended.price_type = match (order.price_type, price) {
+            (price_type, None) => price_type,
+            (PriceType::Limit(_), Some(price)) => PriceType::Limit(price),
+            (price_type, Some(_)) => return Err(OrderError::PriceNotAmendable { price_type }),
         };
-        self.storage_system.add_order_history(&order_history);
-        accounts_system.add_currency_to_account(sell_order.account_id, sell_order.currency_id, order_match.quantity * order_match.price);
-        accounts_system.add_crypto_currency_to_account(sell_order.account_id, sell_order.crypto_currency_id, -(order_match.quantity));
+        let filled_quantity = self.get_filled_quantity(order_id);
+        let open_quantity = quantity.unwrap_or(order.quantity - filled_quantity);
+        if !open_quantity.is_positive() {
+            return Err(OrderError::NonPositiveQuantity { quantity: open_quantity });
+        }
+        amended.quantity = filled_quantity + open_quantity;
+        // The amended order has to pass the same checks as a new one
+        self.validate_order(&amended)?;
+
+        let required = self.worst_case_cost(&amended, open_quantity).unwrap_or(order.held);
+        if required > order.held {
+            let mut settlement = Settlement::default();
+            let shared = self.hold_sharing_order(&order, &settlement).map_or(Amount::ZERO, |other_order| other_order.held);
+            let asset_id = order.held_asset_id();
+            let available = accounts_system.get_available_balance(order.account_id, asset_id);
+            let hold = required.max(shared) - order.held.max(shared);
+            if hold > available {
+                return Err(OrderError::InsufficientFunds { asset_id, required: hold, available });
+            }
+            // Only the hold is stored, the amendment itself once the matcher accepted it
+            self.set_hold(&mut order, required, accounts_system, &mut settlement);
+            settlement.orders.push(order);
+            self.storage_system.settle(&settlement);
+            amended.held = order.held;
+        }
+        Ok(amended)
+    }
 
+    /// Stores the new price and quantity of the order, `quantity` is the new open quantity.
+    /// Funds held beyond the worst case cost of the amended order are released.
+    fn apply_amendment(&mut self, order_id: u64, price_type: PriceType, quantity: Amount, timestamp: SystemTime, accounts_system: &mut AccountSystem, settlement: &mut Settlement) {
+        let mut order: Order = self.storage_system.get_order(order_id).unwrap();
+        order.price_type = price_type;
+        order.quantity = self.get_filled_quantity(order_id) + quantity;
+        self.trim_hold(&mut order, accounts_system, settlement);
+        settlement.order_histories.push(self.new_order_history(&order, OrderHistoryType::Amend, quantity, price_type.limit_price(), None, timestamp));
+        settlement.orders.push(order);
+    }
+
+    /// Releases what the order holds beyond the worst case cost of its open quantity.
+    fn trim_hold(&mut self, order: &mut Order, accounts_system: &mut AccountSystem, settlement: &mut Settlement) {
+        let open_quantity = order.quantity - self.get_filled_quantity(order.id);
+        match self.worst_case_cost(order, open_quantity) {
+            Some(required) if required < order.held => self.set_hold(order, required, accounts_system, settlement),
+            _ => {}
+        }
     }
 
-}
\ No newline at end of file
+    /// Next history row of the order.
+    fn new_order_history(&mut self, order: &Order, history_type: OrderHistoryType, quantity: Amount, price: Option<Amount>, amount: Option<Amount>, timestamp: SystemTime) -> OrderHistory {
+        self.order_history_id += 1;
+        OrderHistory {
+            id: self.order_history_id,
+            order_id: order.id,
+            history_type,
+            oco_group_id: order.oco_group_id,
+            quantity,
+            price,
+            amount,
+            timestamp,
+            status: order.status,
+        }
+    }
+
+}
+
+/// Both orders hold the same asset of the same account.
+fn shares_hold(first: &Order, second: &Order) -> bool {
+    first.account_id == second.account_id && first.held_asset_id() == second.held_asset_id()
+}
+
+fn check_precision(value: Amount, asset: &Asset) -> Result<(), OrderError> {
+    if value.has_precision(asset.precision as u32) {
+        Ok(())
+    } else {
+        Err(OrderError::Precision { value, asset_id: asset.id, precision: asset.precision })
+    }
+}
@@ -1,41 +1,158 @@
 use bincode::{config, decode_from_slice};
-use crate::accounts::{Account, AccountCurrency, AccountCurrencyHistory, AccountCryptoCurrency, AccountCryptoCurrencyHistory};
-use crate::assets::{Currency, CryptoCurrency};
+use crate::accounts::{Account, AccountAsset, AccountAssetHistory, AccountCurrency, AccountCurrencyHistory, AccountCryptoCurrency, AccountCryptoCurrencyHistory};
+use crate::amount::Amount;
+use crate::assets::{Asset, AssetKind, AssetStatus, Currency, CryptoCurrency, Market, MarketStatus, TradingRules};
 
 use std::any::type_name;
-use redb::{Database, Key, Range, ReadableTable, TableDefinition, TypeName, Value};
+use std::collections::HashMap;
+use redb::{Database, Key, Range, ReadableTable, TableDefinition, TableHandle, TypeName, Value, WriteTransaction};
 use std::cmp::Ordering;
 use std::fmt::Debug;
-use crate::orders::{Order, OrderHistory};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::time::SystemTime;
+use crate::fees::{Fee, FeeSchedule};
+use crate::funding::{Deposit, Withdrawal};
+use crate::ledger::{EntryType, JournalEntry, JournalReference, LedgerAccount, Posting};
+use crate::orders::{LegacyOrder, LegacyOrderHistory, LegacyPriceType, OcoGroup, Order, OrderHistory, OrderHistoryType, PriceType, TimeInForce};
 
 pub struct StorageSystem {
     pub accounts_db: Database,
 }
 
+/// Rows that have to be stored together, see `StorageSystem::settle`.
+/// Later rows with the same id replace earlier ones.
+#[derive(Debug, Default)]
+pub struct Settlement {
+    pub orders: Vec<Order>,
+    pub order_histories: Vec<OrderHistory>,
+    pub oco_groups: Vec<OcoGroup>,
+    pub account_assets: Vec<AccountAsset>,
+    pub account_asset_histories: Vec<AccountAssetHistory>,
+    pub journal_entries: Vec<JournalEntry>,
+    pub postings: Vec<Posting>,
+    pub fees: Vec<Fee>,
+    pub deposits: Vec<Deposit>,
+    pub withdrawals: Vec<Withdrawal>,
+}
+
+/// Why a database written before `SCHEMA_VERSION` couldn't be migrated.
+#[derive(Debug, PartialEq, Clone)]
+pub enum MigrationError {
+    /// A row of an old table doesn't decode as the old layout.
+    UndecodableRow { table: String, id: u64 },
+    /// A balance, quantity or price is not a number, infinite or too large for `Amount`.
+    InvalidAmount { table: String, id: u64, value: f64 },
+    /// A row refers to a currency that isn't stored.
+    CurrencyNotFound { table: String, id: u64, currency_id: u64 },
+    CryptoCurrencyNotFound { table: String, id: u64, crypto_currency_id: u64 },
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::UndecodableRow { table, id } => write!(f, "row {id} of table {table} can't be decoded"),
+            MigrationError::InvalidAmount { table, id, value } => write!(f, "row {id} of table {table} has the invalid amount {value}"),
+            MigrationError::CurrencyNotFound { table, id, currency_id } => write!(f, "row {id} of table {table} refers to the missing currency {currency_id}"),
+            MigrationError::CryptoCurrencyNotFound { table, id, crypto_currency_id } => write!(f, "row {id} of table {table} refers to the missing crypto currency {crypto_currency_id}"),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+/// Asset ids the currencies and crypto currencies got by the migration.
+#[derive(Debug, Default)]
+struct LegacyAssetIds {
+    currencies: HashMap<u64, u64>,
+    crypto_currencies: HashMap<u64, u64>,
+}
+
+impl LegacyAssetIds {
+    /// Asset of the currency a row of `table` refers to.
+    fn currency(&self, currency_id: u64, table: &str, id: u64) -> Result<u64, MigrationError> {
+        self.currencies.get(&currency_id).copied()
+            .ok_or_else(|| MigrationError::CurrencyNotFound { table: table.to_string(), id, currency_id })
+    }
+
+    fn crypto_currency(&self, crypto_currency_id: u64, table: &str, id: u64) -> Result<u64, MigrationError> {
+        self.crypto_currencies.get(&crypto_currency_id).copied()
+            .ok_or_else(|| MigrationError::CryptoCurrencyNotFound { table: table.to_string(), id, crypto_currency_id })
+    }
+}
+
+/// Float of a row of `table` rounded to `precision` decimal places.
+fn legacy_amount(value: f64, precision: u8, table: &str, id: u64) -> Result<Amount, MigrationError> {
+    let invalid = || MigrationError::InvalidAmount { table: table.to_string(), id, value };
+    if !value.is_finite() {
+        return Err(invalid());
+    }
+    format!("{value:.*}", precision as usize).parse().map_err(|_| invalid())
+}
+
 const DATABASE_FOLDER_NAME: &str = "database";
 const ACCOUNTS_DB_NAME: &str = "accounts.redb";
 const ACCOUNTS_TABLE: TableDefinition<u64, Bincode<Account>> = TableDefinition::new("accounts");
-const CURRENCIES_TABLE: TableDefinition<u64, Bincode<Currency>> = TableDefinition::new("currencies");
-const CRYPTO_CURRENCIES_TABLE: TableDefinition<u64, Bincode<CryptoCurrency>> = TableDefinition::new("crypto_currencies");
-const ACCOUNT_CURRENCIES_TABLE: TableDefinition<u64, Bincode<AccountCurrency>> = TableDefinition::new("account_currencies");
-const ACCOUNT_CRYPTO_CURRENCIES_TABLE: TableDefinition<u64, Bincode<AccountCryptoCurrency>> = TableDefinition::new("account_crypto_currencies");
-const ACCOUNT_CURRENCY_HISTORIES_TABLE: TableDefinition<u64, Bincode<AccountCurrencyHistory>> = TableDefinition::new("account_currency_histories");
-const ACCOUNT_CRYPTO_CURRENCY_HISTORIES_TABLE: TableDefinition<u64, Bincode<AccountCryptoCurrencyHistory>> = TableDefinition::new("account_crypto_currencies_histories");
+const ASSETS_TABLE: TableDefinition<u64, Bincode<Asset>> = TableDefinition::new("assets");
+const ACCOUNT_ASSETS_TABLE: TableDefinition<u64, Bincode<AccountAsset>> = TableDefinition::new("account_assets");
+const ACCOUNT_ASSET_HISTORIES_TABLE: TableDefinition<u64, Bincode<AccountAssetHistory>> = TableDefinition::new("account_asset_histories");
+// Tables used before currencies and crypto currencies became assets, only read by the migration
+const LEGACY_CURRENCIES_TABLE: TableDefinition<u64, Legacy<Currency, Currency>> = TableDefinition::new("currencies");
+const LEGACY_CRYPTO_CURRENCIES_TABLE: TableDefinition<u64, Legacy<CryptoCurrency, CryptoCurrency>> = TableDefinition::new("crypto_currencies");
+const LEGACY_ACCOUNT_CURRENCIES_TABLE: TableDefinition<u64, Legacy<AccountCurrency, AccountCurrency>> = TableDefinition::new("account_currencies");
+const LEGACY_ACCOUNT_CRYPTO_CURRENCIES_TABLE: TableDefinition<u64, Legacy<AccountCryptoCurrency, AccountCryptoCurrency>> = TableDefinition::new("account_crypto_currencies");
+const LEGACY_ACCOUNT_CURRENCY_HISTORIES_TABLE: TableDefinition<u64, Legacy<AccountCurrencyHistory, AccountCurrencyHistory>> = TableDefinition::new("account_currency_histories");
+const LEGACY_ACCOUNT_CRYPTO_CURRENCY_HISTORIES_TABLE: TableDefinition<u64, Legacy<AccountCryptoCurrencyHistory, AccountCryptoCurrencyHistory>> = TableDefinition::new("account_crypto_currencies_histories");
+// Orders were stored under the same type names before markets
+const LEGACY_ORDERS_TABLE: TableDefinition<u64, Legacy<Order, LegacyOrder>> = TableDefinition::new("orders");
+const LEGACY_ORDER_HISTORIES_TABLE: TableDefinition<u64, Legacy<OrderHistory, LegacyOrderHistory>> = TableDefinition::new("order_histories");
+// Decimal places currencies and crypto currencies get as assets
+const CURRENCY_PRECISION: u8 = 2;
+const CRYPTO_CURRENCY_PRECISION: u8 = 8;
 const ORDERS_TABLE: TableDefinition<u64, Bincode<Order>> = TableDefinition::new("orders");
 const ORDER_HISTORIES_TABLE: TableDefinition<u64, Bincode<OrderHistory>> = TableDefinition::new("order_histories");
+const OCO_GROUPS_TABLE: TableDefinition<u64, Bincode<OcoGroup>> = TableDefinition::new("oco_groups");
+const MARKETS_TABLE: TableDefinition<u64, Bincode<Market>> = TableDefinition::new("markets");
+const JOURNAL_ENTRIES_TABLE: TableDefinition<u64, Bincode<JournalEntry>> = TableDefinition::new("journal_entries");
+const POSTINGS_TABLE: TableDefinition<u64, Bincode<Posting>> = TableDefinition::new("postings");
+// Keyed by market id
+const FEE_SCHEDULES_TABLE: TableDefinition<u64, Bincode<FeeSchedule>> = TableDefinition::new("fee_schedules");
+const FEES_TABLE: TableDefinition<u64, Bincode<Fee>> = TableDefinition::new("fees");
+const DEPOSITS_TABLE: TableDefinition<u64, Bincode<Deposit>> = TableDefinition::new("deposits");
+const WITHDRAWALS_TABLE: TableDefinition<u64, Bincode<Withdrawal>> = TableDefinition::new("withdrawals");
+// Layout of the tables, databases written before it was stored have none
+const SCHEMA_TABLE: TableDefinition<&str, u64> = TableDefinition::new("schema");
+const SCHEMA_VERSION: u64 = 1;
 
 
+impl Default for StorageSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StorageSystem {
+    /// Opens the database in the `database` folder of the working directory.
+    /// Panics if it was written before `SCHEMA_VERSION` and can't be migrated, see `open`.
     pub fn new() -> StorageSystem {
-        if !std::path::Path::new(DATABASE_FOLDER_NAME).exists() {
-            std::fs::create_dir(DATABASE_FOLDER_NAME).unwrap();
+        StorageSystem::open(DATABASE_FOLDER_NAME).unwrap_or_else(|error| panic!("Failed to migrate the database: {error}"))
+    }
+
+    /// Opens the database in the given folder, creating both if they don't exist yet.
+    /// A database written before `SCHEMA_VERSION` is migrated first, it is left as it was if that fails.
+    pub fn open(folder: impl AsRef<Path>) -> Result<StorageSystem, MigrationError> {
+        let folder = folder.as_ref();
+        if !folder.exists() {
+            std::fs::create_dir_all(folder).unwrap();
         }
 
-        let db = Database::create(format!("{DATABASE_FOLDER_NAME}/{ACCOUNTS_DB_NAME}")).unwrap();
+        let db = Database::create(folder.join(ACCOUNTS_DB_NAME)).unwrap();
 
-        StorageSystem {
+        let storage_system = StorageSystem {
             accounts_db: db,
-        }
+        };
+        storage_system.migrate_schema()?;
+        Ok(storage_system)
     }
 
     pub fn load_accounts(&self) -> Vec<Account> {
@@ -89,9 +206,9 @@ impl StorageSystem {
         write_txn.commit().unwrap();
     }
 
-    pub fn get_last_currency(&self) -> Option<Currency> {
+    pub fn get_last_asset(&self) -> Option<Asset> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(ASSETS_TABLE);
         match table_opt {
             Ok(table) => {
                 let last_opt = table.last().unwrap();
@@ -110,20 +227,25 @@ impl StorageSystem {
         }
     }
 
-    pub fn get_last_crypto_currency(&self) -> Option<CryptoCurrency> {
+    pub fn add_asset(&self, asset: &Asset) {
+        let write_txn = self.accounts_db.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_table(ASSETS_TABLE).unwrap();
+            table.insert(&asset.id, asset).unwrap();
+        }
+        write_txn.commit().unwrap();
+    }
+
+    pub fn update_asset(&self, asset: &Asset) {
+        self.add_asset(asset);
+    }
+
+    pub fn get_asset(&self, asset_id: u64) -> Option<Asset> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(CRYPTO_CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(ASSETS_TABLE);
         match table_opt {
             Ok(table) => {
-                let last_opt = table.last().unwrap();
-                match last_opt {
-                    None => {
-                        None
-                    },
-                    Some(i) => {
-                        Some(i.1.value())
-                    }
-                }
+                table.get(&asset_id).unwrap().map(|asset| asset.value())
             }
             Err(_) => {
                 None
@@ -131,37 +253,36 @@ impl StorageSystem {
         }
     }
 
-    pub fn add_currency(&self, currency: &Currency) {
-        let write_txn = self.accounts_db.begin_write().unwrap();
-        {
-            let mut table = write_txn.open_table(CURRENCIES_TABLE).unwrap();
-            table.insert(&currency.id, currency).unwrap();
-        }
-        write_txn.commit().unwrap();
-    }
-
-
-    pub fn add_crypto_currency(&self, crypto_currency: &CryptoCurrency) {
-        let write_txn = self.accounts_db.begin_write().unwrap();
-        {
-            let mut table = write_txn.open_table(CRYPTO_CURRENCIES_TABLE).unwrap();
-            table.insert(&crypto_currency.id, crypto_currency).unwrap();
+    pub fn load_assets(&self) -> Vec<Asset> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(ASSETS_TABLE);
+        match table_opt {
+            Ok(table) => {
+                let iter: Range<u64, Bincode<Asset>> = table.iter().unwrap();
+                let mut assets = vec![];
+                for acc in iter {
+                    assets.push(acc.unwrap().1.value());
+                }
+                assets
+            }
+            Err(_) => {
+                vec![]
+            }
         }
-        write_txn.commit().unwrap();
     }
 
-    pub fn get_currency(&self, currency_id: u64) -> Option<Currency> {
+    pub fn get_last_account_asset(&self) -> Option<AccountAsset> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(ACCOUNT_ASSETS_TABLE);
         match table_opt {
             Ok(table) => {
-                let currency_opt = table.get(&currency_id).unwrap();
-                match currency_opt {
-                    Some(currency) => {
-                        Some(currency.value())
-                    }
+                let last_opt = table.last().unwrap();
+                match last_opt {
                     None => {
                         None
+                    },
+                    Some(i) => {
+                        Some(i.1.value())
                     }
                 }
             }
@@ -171,18 +292,18 @@ impl StorageSystem {
         }
     }
 
-    pub fn get_crypto_currency(&self, crypto_currency_id: u64) -> Option<CryptoCurrency> {
+    pub fn get_last_account_asset_history(&self) -> Option<AccountAssetHistory> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(CRYPTO_CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(ACCOUNT_ASSET_HISTORIES_TABLE);
         match table_opt {
             Ok(table) => {
-                let crypto_currency_opt = table.get(&crypto_currency_id).unwrap();
-                match crypto_currency_opt {
-                    Some(crypto_currency) => {
-                        Some(crypto_currency.value())
-                    }
+                let last_opt = table.last().unwrap();
+                match last_opt {
                     None => {
                         None
+                    },
+                    Some(i) => {
+                        Some(i.1.value())
                     }
                 }
             }
@@ -192,17 +313,30 @@ impl StorageSystem {
         }
     }
 
-    pub fn load_currencies(&self) -> Vec<Currency> {
+    pub fn add_account_asset(&self, account_asset: &AccountAsset) {
+        let write_txn = self.accounts_db.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_table(ACCOUNT_ASSETS_TABLE).unwrap();
+            table.insert(&account_asset.id, account_asset).unwrap();
+        }
+        write_txn.commit().unwrap();
+    }
+
+    pub fn update_account_asset(&self, account_asset: &AccountAsset) {
+        self.add_account_asset(account_asset);
+    }
+
+    pub fn load_account_assets(&self) -> Vec<AccountAsset> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(ACCOUNT_ASSETS_TABLE);
         match table_opt {
             Ok(table) => {
-                let iter: Range<u64, Bincode<Currency>> = table.iter().unwrap();
-                let mut currencies = vec![];
+                let iter: Range<u64, Bincode<AccountAsset>> = table.iter().unwrap();
+                let mut account_assets = vec![];
                 for acc in iter {
-                    currencies.push(acc.unwrap().1.value());
+                    account_assets.push(acc.unwrap().1.value());
                 }
-                currencies
+                account_assets
             }
             Err(_) => {
                 vec![]
@@ -210,27 +344,37 @@ impl StorageSystem {
         }
     }
 
-    pub fn load_crypto_currencies(&self) -> Vec<CryptoCurrency> {
+    pub fn get_account_asset(&self, account_id: u64, asset_id: u64) -> Option<AccountAsset> {
+        self.load_account_assets().into_iter().find(|acc| acc.account_id == account_id && acc.asset_id == asset_id)
+    }
+
+    pub fn get_account_assets_by_account_id(&self, account_id: u64) -> Vec<AccountAsset> {
+        let account_assets:Vec<AccountAsset> = self.load_account_assets();
+        let mut account_assets_by_account_id = vec![];
+        for acc in account_assets {
+            if acc.account_id == account_id {
+                account_assets_by_account_id.push(acc);
+            }
+        }
+        account_assets_by_account_id
+    }
+
+    pub fn get_account_asset_by_id(&self, account_asset_id: u64) -> Option<AccountAsset> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(CRYPTO_CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(ACCOUNT_ASSETS_TABLE);
         match table_opt {
             Ok(table) => {
-                let iter: Range<u64, Bincode<CryptoCurrency>> = table.iter().unwrap();
-                let mut crypto_currencies = vec![];
-                for acc in iter {
-                    crypto_currencies.push(acc.unwrap().1.value());
-                }
-                crypto_currencies
+                table.get(&account_asset_id).unwrap().map(|account_asset| account_asset.value())
             }
             Err(_) => {
-                vec![]
+                None
             }
         }
     }
 
-    pub fn get_last_account_currency(&self) -> Option<AccountCurrency> {
+    pub fn get_last_order(&self) -> Option<Order> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ACCOUNT_CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(ORDERS_TABLE);
         match table_opt {
             Ok(table) => {
                 let last_opt = table.last().unwrap();
@@ -249,9 +393,9 @@ impl StorageSystem {
         }
     }
 
-    pub fn get_last_account_currency_history(&self) -> Option<AccountCurrencyHistory> {
+    pub fn get_last_order_history(&self) -> Option<OrderHistory> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ACCOUNT_CURRENCY_HISTORIES_TABLE);
+        let table_opt = read_txn.open_table(ORDER_HISTORIES_TABLE);
         match table_opt {
             Ok(table) => {
                 let last_opt = table.last().unwrap();
@@ -270,9 +414,80 @@ impl StorageSystem {
         }
     }
 
-    pub fn get_last_account_crypto_currency(&self) -> Option<AccountCryptoCurrency> {
+    pub fn add_order(&self, order: &Order) {
+        let write_txn = self.accounts_db.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_table(ORDERS_TABLE).unwrap();
+            table.insert(&order.id, order).unwrap();
+        }
+        write_txn.commit().unwrap();
+    }
+
+    pub fn update_order(&self, order: &Order) {
+        self.add_order(order);
+    }
+
+    pub fn add_order_history(&self, order_history: &OrderHistory) {
+        let write_txn = self.accounts_db.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_table(ORDER_HISTORIES_TABLE).unwrap();
+            table.insert(&order_history.id, order_history).unwrap();
+        }
+        write_txn.commit().unwrap();
+    }
+
+    /// Writes all rows of the settlement in one transaction, e.g. both legs of a trade together with
+    /// the order updates and history rows. A crash never leaves only part of them stored.
+    pub fn settle(&self, settlement: &Settlement) {
+        let write_txn = self.accounts_db.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_table(ORDERS_TABLE).unwrap();
+            for order in settlement.orders.iter() {
+                table.insert(&order.id, order).unwrap();
+            }
+            let mut table = write_txn.open_table(ORDER_HISTORIES_TABLE).unwrap();
+            for order_history in settlement.order_histories.iter() {
+                table.insert(&order_history.id, order_history).unwrap();
+            }
+            let mut table = write_txn.open_table(OCO_GROUPS_TABLE).unwrap();
+            for oco_group in settlement.oco_groups.iter() {
+                table.insert(&oco_group.id, oco_group).unwrap();
+            }
+            let mut table = write_txn.open_table(ACCOUNT_ASSETS_TABLE).unwrap();
+            for account_asset in settlement.account_assets.iter() {
+                table.insert(&account_asset.id, account_asset).unwrap();
+            }
+            let mut table = write_txn.open_table(ACCOUNT_ASSET_HISTORIES_TABLE).unwrap();
+            for account_asset_history in settlement.account_asset_histories.iter() {
+                table.insert(&account_asset_history.id, account_asset_history).unwrap();
+            }
+            let mut table = write_txn.open_table(JOURNAL_ENTRIES_TABLE).unwrap();
+            for journal_entry in settlement.journal_entries.iter() {
+                table.insert(&journal_entry.id, journal_entry).unwrap();
+            }
+            let mut table = write_txn.open_table(POSTINGS_TABLE).unwrap();
+            for posting in settlement.postings.iter() {
+                table.insert(&posting.id, posting).unwrap();
+            }
+            let mut table = write_txn.open_table(FEES_TABLE).unwrap();
+            for fee in settlement.fees.iter() {
+                table.insert(&fee.id, fee).unwrap();
+            }
+            let mut table = write_txn.open_table(DEPOSITS_TABLE).unwrap();
+            for deposit in settlement.deposits.iter() {
+                table.insert(&deposit.id, deposit).unwrap();
+            }
+            let mut table = write_txn.open_table(WITHDRAWALS_TABLE).unwrap();
+            for withdrawal in settlement.withdrawals.iter() {
+                table.insert(&withdrawal.id, withdrawal).unwrap();
+            }
+        }
+        write_txn.commit().unwrap();
+    }
+
+    pub fn get_last_journal_entry(&self) -> Option<JournalEntry> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ACCOUNT_CRYPTO_CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(JOURNAL_ENTRIES_TABLE);
         match table_opt {
             Ok(table) => {
                 let last_opt = table.last().unwrap();
@@ -291,9 +506,27 @@ impl StorageSystem {
         }
     }
 
-    pub fn get_last_account_crypto_currency_history(&self) -> Option<AccountCryptoCurrencyHistory> {
+    pub fn load_journal_entries(&self) -> Vec<JournalEntry> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ACCOUNT_CRYPTO_CURRENCY_HISTORIES_TABLE);
+        let table_opt = read_txn.open_table(JOURNAL_ENTRIES_TABLE);
+        match table_opt {
+            Ok(table) => {
+                let iter: Range<u64, Bincode<JournalEntry>> = table.iter().unwrap();
+                let mut journal_entries = vec![];
+                for acc in iter {
+                    journal_entries.push(acc.unwrap().1.value());
+                }
+                journal_entries
+            }
+            Err(_) => {
+                vec![]
+            }
+        }
+    }
+
+    pub fn get_last_posting(&self) -> Option<Posting> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(POSTINGS_TABLE);
         match table_opt {
             Ok(table) => {
                 let last_opt = table.last().unwrap();
@@ -312,131 +545,127 @@ impl StorageSystem {
         }
     }
 
-    pub fn add_account_currency(&self, account_currency: &AccountCurrency) {
-        let write_txn = self.accounts_db.begin_write().unwrap();
-        {
-            let mut table = write_txn.open_table(ACCOUNT_CURRENCIES_TABLE).unwrap();
-            table.insert(&account_currency.id, account_currency).unwrap();
-        }
-        write_txn.commit().unwrap();
-    }
-
-    pub fn load_account_currencies(&self) -> Vec<AccountCurrency> {
+    pub fn load_postings(&self) -> Vec<Posting> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ACCOUNT_CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(POSTINGS_TABLE);
         match table_opt {
             Ok(table) => {
-                let iter: Range<u64, Bincode<AccountCurrency>> = table.iter().unwrap();
-                let mut account_currencies = vec![];
+                let iter: Range<u64, Bincode<Posting>> = table.iter().unwrap();
+                let mut postings = vec![];
                 for acc in iter {
-                    account_currencies.push(acc.unwrap().1.value());
+                    postings.push(acc.unwrap().1.value());
                 }
-                account_currencies
+                postings
             }
             Err(_) => {
                 vec![]
             }
         }
     }
-    pub fn get_account_currency(&self, account_id: u64, currency_id: u64) -> Option<AccountCurrency> {
-        let account_currencies:Vec<AccountCurrency> = self.load_account_currencies();
-        for acc in account_currencies {
-            if acc.account_id == account_id && acc.currency_id == currency_id {
-                return Some(acc);
-            }
-        }
-        None
-    }
 
-    pub fn get_account_currency_by_account_id(&self, account_id: u64) -> Vec<AccountCurrency> {
-        let account_currencies:Vec<AccountCurrency> = self.load_account_currencies();
-        let mut account_currencies_by_account_id = vec![];
-        for acc in account_currencies {
-            if acc.account_id == account_id {
-                account_currencies_by_account_id.push(acc);
+    pub fn get_postings_by_journal_entry_id(&self, journal_entry_id: u64) -> Vec<Posting> {
+        let postings:Vec<Posting> = self.load_postings();
+        let mut postings_by_journal_entry_id = vec![];
+        for posting in postings {
+            if posting.journal_entry_id == journal_entry_id {
+                postings_by_journal_entry_id.push(posting);
             }
         }
-        account_currencies_by_account_id
+        postings_by_journal_entry_id
     }
 
-    pub fn add_account_currency_history(&self, account_currency_history: &AccountCurrencyHistory) {
+    pub fn set_fee_schedule(&self, fee_schedule: &FeeSchedule) {
         let write_txn = self.accounts_db.begin_write().unwrap();
         {
-            let mut table = write_txn.open_table(ACCOUNT_CURRENCY_HISTORIES_TABLE).unwrap();
-            table.insert(&account_currency_history.id, account_currency_history).unwrap();
+            let mut table = write_txn.open_table(FEE_SCHEDULES_TABLE).unwrap();
+            table.insert(&fee_schedule.market_id, fee_schedule).unwrap();
         }
         write_txn.commit().unwrap();
     }
 
-    pub fn update_account_currency(&self, account_currency: &AccountCurrency) {
-        let write_txn = self.accounts_db.begin_write().unwrap();
-        {
-            let mut table = write_txn.open_table(ACCOUNT_CURRENCIES_TABLE).unwrap();
-            table.insert(&account_currency.id, account_currency).unwrap();
+    pub fn get_fee_schedule(&self, market_id: u64) -> Option<FeeSchedule> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(FEE_SCHEDULES_TABLE);
+        match table_opt {
+            Ok(table) => {
+                table.get(&market_id).unwrap().map(|fee_schedule| fee_schedule.value())
+            }
+            Err(_) => {
+                None
+            }
         }
-        write_txn.commit().unwrap();
     }
 
-    pub fn add_account_crypto_currency(&self, account_crypto_currency: &AccountCryptoCurrency) {
+    pub fn remove_fee_schedule(&self, market_id: u64) -> Option<FeeSchedule> {
         let write_txn = self.accounts_db.begin_write().unwrap();
-        {
-            let mut table = write_txn.open_table(ACCOUNT_CRYPTO_CURRENCIES_TABLE).unwrap();
-            table.insert(&account_crypto_currency.id, account_crypto_currency).unwrap();
-        }
+        let fee_schedule = {
+            let mut table = write_txn.open_table(FEE_SCHEDULES_TABLE).unwrap();
+            let fee_schedule = table.remove(&market_id).unwrap().map(|fee_schedule| fee_schedule.value());
+            fee_schedule
+        };
         write_txn.commit().unwrap();
+        fee_schedule
     }
 
-    pub fn load_account_crypto_currencies(&self) -> Vec<AccountCryptoCurrency> {
+    pub fn get_last_fee(&self) -> Option<Fee> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ACCOUNT_CRYPTO_CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(FEES_TABLE);
         match table_opt {
             Ok(table) => {
-                let iter: Range<u64, Bincode<AccountCryptoCurrency>> = table.iter().unwrap();
-                let mut account_crypto_currencies = vec![];
-                for acc in iter {
-                    account_crypto_currencies.push(acc.unwrap().1.value());
+                let last_opt = table.last().unwrap();
+                match last_opt {
+                    None => {
+                        None
+                    },
+                    Some(i) => {
+                        Some(i.1.value())
+                    }
                 }
-                account_crypto_currencies
             }
             Err(_) => {
-                vec![]
+                None
             }
         }
     }
 
-    pub fn get_account_crypto_currency(&self, account_id: u64, crypto_currency_id: u64) -> Option<AccountCryptoCurrency> {
-        let account_crypto_currencies:Vec<AccountCryptoCurrency> = self.load_account_crypto_currencies();
-        for acc in account_crypto_currencies {
-            if acc.account_id == account_id && acc.crypto_currency_id == crypto_currency_id {
-                return Some(acc);
+    pub fn load_fees(&self) -> Vec<Fee> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(FEES_TABLE);
+        match table_opt {
+            Ok(table) => {
+                let iter: Range<u64, Bincode<Fee>> = table.iter().unwrap();
+                let mut fees = vec![];
+                for acc in iter {
+                    fees.push(acc.unwrap().1.value());
+                }
+                fees
+            }
+            Err(_) => {
+                vec![]
             }
         }
-        None
     }
 
-    pub fn get_account_crypto_currencies_by_account_id(&self, account_id: u64) -> Vec<AccountCryptoCurrency> {
-        let account_crypto_currencies:Vec<AccountCryptoCurrency> = self.load_account_crypto_currencies();
-        let mut account_crypto_currencies_by_account_id = vec![];
-        for acc in account_crypto_currencies {
-            if acc.account_id == account_id {
-                account_crypto_currencies_by_account_id.push(acc);
-            }
-        }
-        account_crypto_currencies_by_account_id
+    pub fn get_fees_by_order_id(&self, order_id: u64) -> Vec<Fee> {
+        self.load_fees().into_iter().filter(|fee| fee.order_id == order_id).collect()
+    }
+
+    pub fn get_fees_by_account_id(&self, account_id: u64) -> Vec<Fee> {
+        self.load_fees().into_iter().filter(|fee| fee.account_id == account_id).collect()
     }
 
-    pub fn get_account_crypto_currency_by_id(&self, account_crypto_currency_id: u64) -> Option<AccountCryptoCurrency> {
+    pub fn get_last_deposit(&self) -> Option<Deposit> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ACCOUNT_CRYPTO_CURRENCIES_TABLE);
+        let table_opt = read_txn.open_table(DEPOSITS_TABLE);
         match table_opt {
             Ok(table) => {
-                let account_crypto_currency_opt = table.get(&account_crypto_currency_id).unwrap();
-                match account_crypto_currency_opt {
-                    Some(account_crypto_currency) => {
-                        Some(account_crypto_currency.value())
-                    }
+                let last_opt = table.last().unwrap();
+                match last_opt {
                     None => {
                         None
+                    },
+                    Some(i) => {
+                        Some(i.1.value())
                     }
                 }
             }
@@ -446,22 +675,48 @@ impl StorageSystem {
         }
     }
 
-    pub fn update_account_crypto_currency(&self, account_crypto_currency: AccountCryptoCurrency) {
-        self.add_account_crypto_currency(&account_crypto_currency);
+    pub fn get_deposit(&self, deposit_id: u64) -> Option<Deposit> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(DEPOSITS_TABLE);
+        match table_opt {
+            Ok(table) => {
+                table.get(&deposit_id).unwrap().map(|deposit| deposit.value())
+            }
+            Err(_) => {
+                None
+            }
+        }
     }
 
-    pub fn add_account_crypto_currency_history(&self, account_crypto_currency_history: &AccountCryptoCurrencyHistory) {
-        let write_txn = self.accounts_db.begin_write().unwrap();
-        {
-            let mut table = write_txn.open_table(ACCOUNT_CRYPTO_CURRENCY_HISTORIES_TABLE).unwrap();
-            table.insert(&account_crypto_currency_history.id, account_crypto_currency_history).unwrap();
+    pub fn load_deposits(&self) -> Vec<Deposit> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(DEPOSITS_TABLE);
+        match table_opt {
+            Ok(table) => {
+                let iter: Range<u64, Bincode<Deposit>> = table.iter().unwrap();
+                let mut deposits = vec![];
+                for acc in iter {
+                    deposits.push(acc.unwrap().1.value());
+                }
+                deposits
+            }
+            Err(_) => {
+                vec![]
+            }
         }
-        write_txn.commit().unwrap();
     }
 
-    pub fn get_last_order(&self) -> Option<Order> {
+    pub fn get_deposits_by_account_id(&self, account_id: u64) -> Vec<Deposit> {
+        self.load_deposits().into_iter().filter(|deposit| deposit.account_id == account_id).collect()
+    }
+
+    pub fn get_deposit_by_reference(&self, asset_id: u64, reference: &str) -> Option<Deposit> {
+        self.load_deposits().into_iter().find(|deposit| deposit.asset_id == asset_id && deposit.reference == reference)
+    }
+
+    pub fn get_last_withdrawal(&self) -> Option<Withdrawal> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ORDERS_TABLE);
+        let table_opt = read_txn.open_table(WITHDRAWALS_TABLE);
         match table_opt {
             Ok(table) => {
                 let last_opt = table.last().unwrap();
@@ -480,20 +735,12 @@ impl StorageSystem {
         }
     }
 
-    pub fn get_last_order_history(&self) -> Option<OrderHistory> {
+    pub fn get_withdrawal(&self, withdrawal_id: u64) -> Option<Withdrawal> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ORDER_HISTORIES_TABLE);
+        let table_opt = read_txn.open_table(WITHDRAWALS_TABLE);
         match table_opt {
             Ok(table) => {
-                let last_opt = table.last().unwrap();
-                match last_opt {
-                    None => {
-                        None
-                    },
-                    Some(i) => {
-                        Some(i.1.value())
-                    }
-                }
+                table.get(&withdrawal_id).unwrap().map(|withdrawal| withdrawal.value())
             }
             Err(_) => {
                 None
@@ -501,22 +748,26 @@ impl StorageSystem {
         }
     }
 
-    pub fn add_order(&self, order: &Order) {
-        let write_txn = self.accounts_db.begin_write().unwrap();
-        {
-            let mut table = write_txn.open_table(ORDERS_TABLE).unwrap();
-            table.insert(&order.id, order).unwrap();
+    pub fn load_withdrawals(&self) -> Vec<Withdrawal> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(WITHDRAWALS_TABLE);
+        match table_opt {
+            Ok(table) => {
+                let iter: Range<u64, Bincode<Withdrawal>> = table.iter().unwrap();
+                let mut withdrawals = vec![];
+                for acc in iter {
+                    withdrawals.push(acc.unwrap().1.value());
+                }
+                withdrawals
+            }
+            Err(_) => {
+                vec![]
+            }
         }
-        write_txn.commit().unwrap();
     }
 
-    pub fn add_order_history(&self, order_history: &OrderHistory) {
-        let write_txn = self.accounts_db.begin_write().unwrap();
-        {
-            let mut table = write_txn.open_table(ORDER_HISTORIES_TABLE).unwrap();
-            table.insert(&order_history.id, order_history).unwrap();
-        }
-        write_txn.commit().unwrap();
+    pub fn get_withdrawals_by_account_id(&self, account_id: u64) -> Vec<Withdrawal> {
+        self.load_withdrawals().into_iter().filter(|withdrawal| withdrawal.account_id == account_id).collect()
     }
 
     pub fn get_order(&self, order_id: u64) -> Option<Order> {
@@ -524,15 +775,7 @@ impl StorageSystem {
         let table_opt = read_txn.open_table(ORDERS_TABLE);
         match table_opt {
             Ok(table) => {
-                let order_opt = table.get(&order_id).unwrap();
-                match order_opt {
-                    Some(order) => {
-                        Some(order.value())
-                    }
-                    None => {
-                        None
-                    }
-                }
+                table.get(&order_id).unwrap().map(|order| order.value())
             }
             Err(_) => {
                 None
@@ -568,17 +811,94 @@ impl StorageSystem {
         }
     }
 
-    pub fn load_account_currency_histories(&self) -> Vec<AccountCurrencyHistory> {
+    /// Order histories stored after the given one, in the order they were stored.
+    pub fn load_order_histories_after(&self, order_history_id: u64) -> Vec<OrderHistory> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(ORDER_HISTORIES_TABLE);
+        match table_opt {
+            Ok(table) => {
+                let iter: Range<u64, Bincode<OrderHistory>> = table.range(order_history_id + 1..).unwrap();
+                let mut order_histories = vec![];
+                for acc in iter {
+                    order_histories.push(acc.unwrap().1.value());
+                }
+                order_histories
+            }
+            Err(_) => {
+                vec![]
+            }
+        }
+    }
+
+    /// Order histories from `timestamp` on, read backwards from the newest one so older rows are never loaded.
+    pub fn load_order_histories_since(&self, timestamp: SystemTime) -> Vec<OrderHistory> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(ORDER_HISTORIES_TABLE);
+        match table_opt {
+            Ok(table) => {
+                let iter: Range<u64, Bincode<OrderHistory>> = table.iter().unwrap();
+                let mut order_histories = vec![];
+                for acc in iter.rev() {
+                    let order_history = acc.unwrap().1.value();
+                    if order_history.timestamp < timestamp {
+                        break;
+                    }
+                    order_histories.push(order_history);
+                }
+                order_histories.reverse();
+                order_histories
+            }
+            Err(_) => {
+                vec![]
+            }
+        }
+    }
+
+    pub fn get_last_oco_group(&self) -> Option<OcoGroup> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ACCOUNT_CURRENCY_HISTORIES_TABLE);
+        let table_opt = read_txn.open_table(OCO_GROUPS_TABLE);
         match table_opt {
             Ok(table) => {
-                let iter: Range<u64, Bincode<AccountCurrencyHistory>> = table.iter().unwrap();
-                let mut account_currency_histories = vec![];
+                let last_opt = table.last().unwrap();
+                match last_opt {
+                    None => {
+                        None
+                    },
+                    Some(i) => {
+                        Some(i.1.value())
+                    }
+                }
+            }
+            Err(_) => {
+                None
+            }
+        }
+    }
+
+    pub fn get_oco_group(&self, oco_group_id: u64) -> Option<OcoGroup> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(OCO_GROUPS_TABLE);
+        match table_opt {
+            Ok(table) => {
+                table.get(&oco_group_id).unwrap().map(|oco_group| oco_group.value())
+            }
+            Err(_) => {
+                None
+            }
+        }
+    }
+
+    pub fn load_markets(&self) -> Vec<Market> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(MARKETS_TABLE);
+        match table_opt {
+            Ok(table) => {
+                let iter: Range<u64, Bincode<Market>> = table.iter().unwrap();
+                let mut markets = vec![];
                 for acc in iter {
-                    account_currency_histories.push(acc.unwrap().1.value());
+                    markets.push(acc.unwrap().1.value());
                 }
-                account_currency_histories
+                markets
             }
             Err(_) => {
                 vec![]
@@ -586,44 +906,337 @@ impl StorageSystem {
         }
     }
 
-    pub fn get_currency_history_by_account_id_account_currency_id(&self, account_id: u64, account_currency_id: u64) -> Vec<AccountCurrencyHistory> {
-        let account_currency_histories:Vec<AccountCurrencyHistory> = self.load_account_currency_histories();
-        let mut account_currency_histories_by_account_id = vec![];
-        for acc in account_currency_histories {
-            if acc.account_id == account_id && acc.account_currency_id == account_currency_id {
-                account_currency_histories_by_account_id.push(acc);
+    pub fn get_last_market(&self) -> Option<Market> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(MARKETS_TABLE);
+        match table_opt {
+            Ok(table) => {
+                let last_opt = table.last().unwrap();
+                match last_opt {
+                    None => {
+                        None
+                    },
+                    Some(i) => {
+                        Some(i.1.value())
+                    }
+                }
+            }
+            Err(_) => {
+                None
+            }
+        }
+    }
+
+    pub fn add_market(&self, market: &Market) {
+        let write_txn = self.accounts_db.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_table(MARKETS_TABLE).unwrap();
+            table.insert(&market.id, market).unwrap();
+        }
+        write_txn.commit().unwrap();
+    }
+
+    pub fn update_market(&self, market: &Market) {
+        self.add_market(market);
+    }
+
+    pub fn get_market(&self, market_id: u64) -> Option<Market> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(MARKETS_TABLE);
+        match table_opt {
+            Ok(table) => {
+                table.get(&market_id).unwrap().map(|market| market.value())
+            }
+            Err(_) => {
+                None
             }
         }
-        account_currency_histories_by_account_id
     }
 
+    pub fn remove_market(&self, market_id: u64) -> Option<Market> {
+        let write_txn = self.accounts_db.begin_write().unwrap();
+        let market = {
+            let mut table = write_txn.open_table(MARKETS_TABLE).unwrap();
+            let market = table.remove(&market_id).unwrap().map(|market| market.value());
+            market
+        };
+        write_txn.commit().unwrap();
+        market
+    }
 
-    pub fn load_account_crypto_currency_histories(&self) -> Vec<AccountCryptoCurrencyHistory> {
+    pub fn load_account_asset_histories(&self) -> Vec<AccountAssetHistory> {
         let read_txn = self.accounts_db.begin_read().unwrap();
-        let table_opt = read_txn.open_table(ACCOUNT_CRYPTO_CURRENCY_HISTORIES_TABLE);
+        let table_opt = read_txn.open_table(ACCOUNT_ASSET_HISTORIES_TABLE);
         match table_opt {
             Ok(table) => {
-                let iter: Range<u64, Bincode<AccountCryptoCurrencyHistory>> = table.iter().unwrap();
-                let mut account_crypto_currency_histories = vec![];
+                let iter: Range<u64, Bincode<AccountAssetHistory>> = table.iter().unwrap();
+                let mut account_asset_histories = vec![];
                 for acc in iter {
-                    account_crypto_currency_histories.push(acc.unwrap().1.value());
+                    account_asset_histories.push(acc.unwrap().1.value());
                 }
-                account_crypto_currency_histories
+                account_asset_histories
             }
             Err(_) => {
                 vec![]
             }
         }
     }
-    pub fn get_crypto_currency_history_by_account_id_crypto_currency_id(&self, account_id: u64, crypto_currency_id: u64) -> Vec<AccountCryptoCurrencyHistory> {
-        let account_crypto_currency_histories:Vec<AccountCryptoCurrencyHistory> = self.load_account_crypto_currency_histories();
-        let mut account_crypto_currency_histories_by_account_id = vec![];
-        for acc in account_crypto_currency_histories {
-            if acc.account_id == account_id && acc.crypto_currency_id == crypto_currency_id {
-                account_crypto_currency_histories_by_account_id.push(acc);
+
+    pub fn get_account_asset_histories_by_account_id_asset_id(&self, account_id: u64, asset_id: u64) -> Vec<AccountAssetHistory> {
+        let account_asset_histories:Vec<AccountAssetHistory> = self.load_account_asset_histories();
+        let mut account_asset_histories_by_account_id = vec![];
+        for acc in account_asset_histories {
+            if acc.account_id == account_id && acc.asset_id == asset_id {
+                account_asset_histories_by_account_id.push(acc);
+            }
+        }
+        account_asset_histories_by_account_id
+    }
+
+    /// Brings a database without a schema version to `SCHEMA_VERSION` in one transaction, nothing is changed
+    /// if a legacy row can't be converted.
+    fn migrate_schema(&self) -> Result<(), MigrationError> {
+        if self.get_schema_version().is_some() {
+            return Ok(());
+        }
+        let write_txn = self.accounts_db.begin_write().unwrap();
+        let asset_ids = self.migrate_legacy_assets(&write_txn)?;
+        self.migrate_legacy_orders(&write_txn, &asset_ids)?;
+        {
+            let mut table = write_txn.open_table(SCHEMA_TABLE).unwrap();
+            table.insert("version", SCHEMA_VERSION).unwrap();
+        }
+        write_txn.commit().unwrap();
+        Ok(())
+    }
+
+    fn get_schema_version(&self) -> Option<u64> {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(SCHEMA_TABLE);
+        match table_opt {
+            Ok(table) => {
+                table.get("version").unwrap().map(|version| version.value())
+            }
+            Err(_) => {
+                None
+            }
+        }
+    }
+
+    /// Moves currencies, crypto currencies and the account balances of both from the tables used before
+    /// `Asset` into the asset tables, then drops the old tables. Returns the asset ids the currencies got.
+    fn migrate_legacy_assets(&self, write_txn: &WriteTransaction) -> Result<LegacyAssetIds, MigrationError> {
+        let currencies: Vec<Currency> = self.load_legacy_table(LEGACY_CURRENCIES_TABLE)?;
+        let crypto_currencies: Vec<CryptoCurrency> = self.load_legacy_table(LEGACY_CRYPTO_CURRENCIES_TABLE)?;
+        let account_currencies: Vec<AccountCurrency> = self.load_legacy_table(LEGACY_ACCOUNT_CURRENCIES_TABLE)?;
+        let account_crypto_currencies: Vec<AccountCryptoCurrency> = self.load_legacy_table(LEGACY_ACCOUNT_CRYPTO_CURRENCIES_TABLE)?;
+        let account_currency_histories: Vec<AccountCurrencyHistory> = self.load_legacy_table(LEGACY_ACCOUNT_CURRENCY_HISTORIES_TABLE)?;
+        let account_crypto_currency_histories: Vec<AccountCryptoCurrencyHistory> = self.load_legacy_table(LEGACY_ACCOUNT_CRYPTO_CURRENCY_HISTORIES_TABLE)?;
+        let mut asset_ids = LegacyAssetIds::default();
+        if currencies.is_empty() && crypto_currencies.is_empty() {
+            return Ok(asset_ids);
+        }
+
+        let mut asset_id = self.get_last_asset().map(|asset| asset.id).unwrap_or(0);
+        let mut assets = vec![];
+        for currency in currencies {
+            asset_id += 1;
+            asset_ids.currencies.insert(currency.id, asset_id);
+            assets.push(Asset { id: asset_id, name: currency.symbol.clone(), symbol: currency.symbol, kind: AssetKind::Fiat, precision: CURRENCY_PRECISION, status: AssetStatus::Active });
+        }
+        for crypto_currency in crypto_currencies {
+            asset_id += 1;
+            asset_ids.crypto_currencies.insert(crypto_currency.id, asset_id);
+            assets.push(Asset { id: asset_id, name: crypto_currency.symbol.clone(), symbol: crypto_currency.symbol, kind: AssetKind::Crypto, precision: CRYPTO_CURRENCY_PRECISION, status: AssetStatus::Active });
+        }
+
+        // Balances were floats, they are rounded to the precision the assets get
+        let balances: Vec<(u64, u64, Amount)> = account_currencies.into_iter()
+            .map(|acc| Ok((acc.account_id, asset_ids.currency(acc.currency_id, "account_currencies", acc.id)?, legacy_amount(acc.balance, CURRENCY_PRECISION, "account_currencies", acc.id)?)))
+            .chain(account_crypto_currencies.into_iter().map(|acc| Ok((acc.account_id, asset_ids.crypto_currency(acc.crypto_currency_id, "account_crypto_currencies", acc.id)?, legacy_amount(acc.quantity, CRYPTO_CURRENCY_PRECISION, "account_crypto_currencies", acc.id)?))))
+            .collect::<Result<_, MigrationError>>()?;
+        let mut account_asset_id = self.get_last_account_asset().map(|account_asset| account_asset.id).unwrap_or(0);
+        let account_assets: Vec<AccountAsset> = balances.into_iter()
+            .map(|(account_id, asset_id, balance)| {
+                account_asset_id += 1;
+                AccountAsset { id: account_asset_id, account_id, asset_id, balance, held: Amount::ZERO }
+            })
+            .collect();
+
+        let mut histories: Vec<(u64, u64, Amount, SystemTime)> = account_currency_histories.into_iter()
+            .map(|acc| Ok((acc.account_id, asset_ids.currency(acc.currency_id, "account_currency_histories", acc.id)?, legacy_amount(acc.balance, CURRENCY_PRECISION, "account_currency_histories", acc.id)?, acc.timestamp)))
+            .chain(account_crypto_currency_histories.into_iter().map(|acc| Ok((acc.account_id, asset_ids.crypto_currency(acc.crypto_currency_id, "account_crypto_currencies_histories", acc.id)?, legacy_amount(acc.quantity, CRYPTO_CURRENCY_PRECISION, "account_crypto_currencies_histories", acc.id)?, acc.timestamp))))
+            .collect::<Result<_, MigrationError>>()?;
+        histories.sort_by_key(|history| history.3);
+        let mut account_asset_history_id = self.get_last_account_asset_history().map(|account_asset_history| account_asset_history.id).unwrap_or(0);
+        let account_asset_histories: Vec<AccountAssetHistory> = histories.into_iter()
+            .map(|(account_id, asset_id, balance, timestamp)| {
+                account_asset_history_id += 1;
+                AccountAssetHistory { id: account_asset_history_id, account_id, asset_id, balance, held: Amount::ZERO, timestamp }
+            })
+            .collect();
+
+        // Migrated balances enter the ledger as deposits
+        let mut journal_entry_id = self.get_last_journal_entry().map(|journal_entry| journal_entry.id).unwrap_or(0);
+        let journal_entries: Vec<JournalEntry> = account_assets.iter()
+            .filter(|account_asset| !account_asset.balance.is_zero())
+            .map(|_| {
+                journal_entry_id += 1;
+                JournalEntry { id: journal_entry_id, entry_type: EntryType::Deposit, reference: JournalReference::None, timestamp: SystemTime::now() }
+            })
+            .collect();
+        let mut posting_id = self.get_last_posting().map(|posting| posting.id).unwrap_or(0);
+        let postings: Vec<Posting> = journal_entries.iter()
+            .zip(account_assets.iter().filter(|account_asset| !account_asset.balance.is_zero()))
+            .flat_map(|(journal_entry, account_asset)| [
+                (journal_entry.id, LedgerAccount::Account(account_asset.account_id), account_asset.asset_id, account_asset.balance),
+                (journal_entry.id, LedgerAccount::External, account_asset.asset_id, -account_asset.balance),
+            ])
+            .map(|(journal_entry_id, ledger_account, asset_id, amount)| {
+                posting_id += 1;
+                Posting { id: posting_id, journal_entry_id, ledger_account, asset_id, amount }
+            })
+            .collect();
+
+        {
+            let mut table = write_txn.open_table(ASSETS_TABLE).unwrap();
+            for asset in assets.iter() {
+                table.insert(&asset.id, asset).unwrap();
+            }
+            let mut table = write_txn.open_table(ACCOUNT_ASSETS_TABLE).unwrap();
+            for account_asset in account_assets.iter() {
+                table.insert(&account_asset.id, account_asset).unwrap();
+            }
+            let mut table = write_txn.open_table(ACCOUNT_ASSET_HISTORIES_TABLE).unwrap();
+            for account_asset_history in account_asset_histories.iter() {
+                table.insert(&account_asset_history.id, account_asset_history).unwrap();
+            }
+            let mut table = write_txn.open_table(JOURNAL_ENTRIES_TABLE).unwrap();
+            for journal_entry in journal_entries.iter() {
+                table.insert(&journal_entry.id, journal_entry).unwrap();
+            }
+            let mut table = write_txn.open_table(POSTINGS_TABLE).unwrap();
+            for posting in postings.iter() {
+                table.insert(&posting.id, posting).unwrap();
+            }
+        }
+        write_txn.delete_table(LEGACY_CURRENCIES_TABLE).unwrap();
+        write_txn.delete_table(LEGACY_CRYPTO_CURRENCIES_TABLE).unwrap();
+        write_txn.delete_table(LEGACY_ACCOUNT_CURRENCIES_TABLE).unwrap();
+        write_txn.delete_table(LEGACY_ACCOUNT_CRYPTO_CURRENCIES_TABLE).unwrap();
+        write_txn.delete_table(LEGACY_ACCOUNT_CURRENCY_HISTORIES_TABLE).unwrap();
+        write_txn.delete_table(LEGACY_ACCOUNT_CRYPTO_CURRENCY_HISTORIES_TABLE).unwrap();
+        tracing::info!("Migrated {} assets and {} account balances", assets.len(), account_assets.len());
+        Ok(asset_ids)
+    }
+
+    /// Converts the orders and fills stored before markets. Every pair of crypto currency and currency orders were
+    /// placed for becomes a trading market. Quantities and prices are rounded to the precision of their asset,
+    /// fills keep their quantity only, as the old layout had no price or amount. The old orders never held funds,
+    /// so the converted ones hold nothing either.
+    fn migrate_legacy_orders(&self, write_txn: &WriteTransaction, asset_ids: &LegacyAssetIds) -> Result<(), MigrationError> {
+        let legacy_orders: Vec<LegacyOrder> = self.load_legacy_table(LEGACY_ORDERS_TABLE)?;
+        let legacy_order_histories: Vec<LegacyOrderHistory> = self.load_legacy_table(LEGACY_ORDER_HISTORIES_TABLE)?;
+        if legacy_orders.is_empty() && legacy_order_histories.is_empty() {
+            return Ok(());
+        }
+
+        let mut market_id = self.get_last_market().map(|market| market.id).unwrap_or(0);
+        let mut markets: Vec<Market> = vec![];
+        let mut orders = vec![];
+        for legacy_order in legacy_orders {
+            let base_asset_id = asset_ids.crypto_currency(legacy_order.crypto_currency_id, "orders", legacy_order.id)?;
+            let quote_asset_id = asset_ids.currency(legacy_order.currency_id, "orders", legacy_order.id)?;
+            let market = match markets.iter().find(|market| (market.base_asset_id, market.quote_asset_id) == (base_asset_id, quote_asset_id)) {
+                Some(market) => *market,
+                None => {
+                    market_id += 1;
+                    let market = Market { id: market_id, base_asset_id, quote_asset_id, status: MarketStatus::Trading, trading_rules: TradingRules::default() };
+                    markets.push(market);
+                    market
+                }
+            };
+            let price_type = match legacy_order.price_type {
+                LegacyPriceType::Market => PriceType::Market,
+                LegacyPriceType::Limit(price) => PriceType::Limit(legacy_amount(price, CURRENCY_PRECISION, "orders", legacy_order.id)?),
+            };
+            orders.push(Order {
+                id: legacy_order.id,
+                account_id: legacy_order.account_id,
+                market_id: market.id,
+                trade_type: legacy_order.trade_type,
+                price_type,
+                execution_type: legacy_order.execution_type,
+                time_in_force: TimeInForce::GoodTillCancel,
+                base_asset_id,
+                quote_asset_id,
+                quantity: legacy_amount(legacy_order.quantity, CRYPTO_CURRENCY_PRECISION, "orders", legacy_order.id)?,
+                quote_quantity: None,
+                slippage: None,
+                display_quantity: None,
+                held: Amount::ZERO,
+                post_only: false,
+                oco_group_id: None,
+                timestamp: legacy_order.timestamp,
+                status: legacy_order.status,
+            });
+        }
+        let order_histories: Vec<OrderHistory> = legacy_order_histories.into_iter()
+            .map(|legacy_order_history| Ok(OrderHistory {
+                id: legacy_order_history.id,
+                order_id: legacy_order_history.order_id,
+                history_type: OrderHistoryType::Fill,
+                oco_group_id: None,
+                quantity: legacy_amount(legacy_order_history.quantity, CRYPTO_CURRENCY_PRECISION, "order_histories", legacy_order_history.id)?,
+                price: None,
+                amount: None,
+                timestamp: legacy_order_history.timestamp,
+                status: legacy_order_history.status,
+            }))
+            .collect::<Result<_, MigrationError>>()?;
+
+        // Same table names, the old rows are replaced
+        write_txn.delete_table(LEGACY_ORDERS_TABLE).unwrap();
+        write_txn.delete_table(LEGACY_ORDER_HISTORIES_TABLE).unwrap();
+        {
+            let mut table = write_txn.open_table(MARKETS_TABLE).unwrap();
+            for market in markets.iter() {
+                table.insert(&market.id, market).unwrap();
+            }
+            let mut table = write_txn.open_table(ORDERS_TABLE).unwrap();
+            for order in orders.iter() {
+                table.insert(&order.id, order).unwrap();
+            }
+            let mut table = write_txn.open_table(ORDER_HISTORIES_TABLE).unwrap();
+            for order_history in order_histories.iter() {
+                table.insert(&order_history.id, order_history).unwrap();
+            }
+        }
+        tracing::info!("Migrated {} orders and {} order histories into {} markets", orders.len(), order_histories.len(), markets.len());
+        Ok(())
+    }
+
+    /// Rows of a table of the old layout, none if the table doesn't exist (anymore).
+    fn load_legacy_table<S, T>(&self, table_definition: TableDefinition<u64, Legacy<S, T>>) -> Result<Vec<T>, MigrationError>
+        where
+            S: Debug + 'static,
+            T: Debug + bincode::Decode<()> + bincode::Encode + 'static,
+    {
+        let read_txn = self.accounts_db.begin_read().unwrap();
+        let table_opt = read_txn.open_table(table_definition);
+        match table_opt {
+            Ok(table) => {
+                table.iter().unwrap()
+                    .map(|row| {
+                        let (id, value) = row.unwrap();
+                        value.value().ok_or_else(|| MigrationError::UndecodableRow { table: table_definition.name().to_string(), id: id.value() })
+                    })
+                    .collect()
+            }
+            Err(_) => {
+                Ok(vec![])
             }
         }
-        account_crypto_currency_histories_by_account_id
     }
 
 }
@@ -636,7 +1249,7 @@ pub struct Bincode<T>(pub T);
 
 impl<T> Value for Bincode<T>
     where
-        T: Debug + bincode::Decode + bincode::Encode,
+        T: Debug + bincode::Decode<()> + bincode::Encode,
 {
     type SelfType<'a> = T
         where
@@ -676,9 +1289,53 @@ impl<T> Value for Bincode<T>
 
 impl<T> Key for Bincode<T>
     where
-        T: Debug +  Ord + bincode::Decode + bincode::Encode,
+        T: Debug +  Ord + bincode::Decode<()> + bincode::Encode,
 {
     fn compare(data1: &[u8], data2: &[u8]) -> Ordering {
         Self::from_bytes(data1).cmp(&Self::from_bytes(data2))
     }
-}
\ No newline at end of file
+}
+
+/// Row of a table written before `SCHEMA_VERSION`: stored as `Bincode<S>`, read as `T`.
+/// Rows that don't decode are `None` instead of a panic.
+#[derive(Debug)]
+pub struct Legacy<S, T>(PhantomData<(S, T)>);
+
+impl<S, T> Value for Legacy<S, T>
+    where
+        S: Debug,
+        T: Debug + bincode::Decode<()> + bincode::Encode,
+{
+    type SelfType<'a> = Option<T>
+        where
+            Self: 'a;
+
+    type AsBytes<'a> = Vec<u8>
+        where
+            Self: 'a;
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+        where
+            Self: 'a,
+    {
+        let config = config::standard();
+        decode_from_slice(data, config).ok().map(|(decoded, _)| decoded)
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+        where
+            Self: 'a,
+            Self: 'b,
+    {
+        let config = config::standard();
+        value.as_ref().map(|value| bincode::encode_to_vec(value, config).unwrap()).unwrap_or_default()
+    }
+
+    fn type_name() -> TypeName {
+        TypeName::new(&format!("Bincode<{}>", type_name::<S>()))
+    }
+}
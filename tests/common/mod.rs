@@ -0,0 +1,25 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static LAST_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Database folder of a single test in the temp directory, removed once the test is done.
+/// The `database` folder of the working directory is never touched.
+pub struct TempDatabase {
+    pub path: PathBuf,
+}
+
+impl TempDatabase {
+    pub fn new() -> TempDatabase {
+        let id = LAST_ID.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("kubera-test-{}-{id}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        TempDatabase { path }
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
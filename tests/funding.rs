@@ -0,0 +1,237 @@
+mod common;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+use kubera::accounts::{Account, AccountSystem};
+use kubera::amount::Amount;
+use kubera::assets::{Asset, AssetKind, AssetStatus, AssetSystem};
+use kubera::funding::{FundingAdapter, FundingError, FundingStatus, TransactionStatus, Withdrawal};
+use kubera::storage::StorageSystem;
+use common::TempDatabase;
+
+/// Local stand-in for a blockchain node and a bank: every `mine` adds a confirmation to every transaction.
+struct MockFundingAdapter {
+    crypto_asset_ids: HashSet<u64>,
+    confirmations: HashMap<String, u32>,
+    failed: HashSet<String>,
+    last_transaction_id: u64,
+}
+
+impl MockFundingAdapter {
+    fn new(crypto_asset_ids: HashSet<u64>) -> MockFundingAdapter {
+        MockFundingAdapter {
+            crypto_asset_ids,
+            confirmations: HashMap::new(),
+            failed: HashSet::new(),
+            last_transaction_id: 0,
+        }
+    }
+
+    /// A transaction arriving from outside, e.g. a deposit.
+    fn receive(&mut self) -> String {
+        self.last_transaction_id += 1;
+        let reference = format!("tx{}", self.last_transaction_id);
+        self.confirmations.insert(reference.clone(), 0);
+        reference
+    }
+
+    fn mine(&mut self) {
+        for confirmations in self.confirmations.values_mut() {
+            *confirmations += 1;
+        }
+    }
+
+    fn fail(&mut self, reference: &str) {
+        self.failed.insert(reference.to_string());
+    }
+
+    /// The node lost track of the transaction, e.g. after a restart.
+    fn forget(&mut self, reference: &str) {
+        self.confirmations.remove(reference);
+    }
+}
+
+impl FundingAdapter for MockFundingAdapter {
+    fn required_confirmations(&self, asset_id: u64) -> u32 {
+        if self.crypto_asset_ids.contains(&asset_id) {
+            3
+        } else {
+            0
+        }
+    }
+
+    fn get_confirmations(&self, reference: &str) -> TransactionStatus {
+        if self.failed.contains(reference) {
+            return TransactionStatus::Failed;
+        }
+        match self.confirmations.get(reference) {
+            Some(confirmations) => TransactionStatus::Confirmations(*confirmations),
+            None => TransactionStatus::Unknown,
+        }
+    }
+
+    fn send_withdrawal(&mut self, withdrawal: &Withdrawal) -> Result<String, String> {
+        if withdrawal.destination.is_empty() {
+            return Err("no destination".to_string());
+        }
+        Ok(self.receive())
+    }
+}
+
+/// An account without funds, USD and BTC assets and an adapter where BTC needs 3 confirmations.
+struct Exchange {
+    accounts_system: AccountSystem,
+    storage_system: Arc<StorageSystem>,
+    adapter: MockFundingAdapter,
+    account_id: u64,
+    usd_id: u64,
+    btc_id: u64,
+    // Dropped last, once the database is closed
+    _database: TempDatabase,
+}
+
+impl Exchange {
+    fn new() -> Exchange {
+        let database = TempDatabase::new();
+        let storage_system = Arc::new(StorageSystem::open(&database.path).unwrap());
+        let mut assets_system = AssetSystem::new(storage_system.clone());
+        let usd_id = assets_system.create_asset(Asset { id: 0, symbol: "USD".to_string(), name: "US Dollar".to_string(), kind: AssetKind::Fiat, precision: 2, status: AssetStatus::Active });
+        let btc_id = assets_system.create_asset(Asset { id: 0, symbol: "BTC".to_string(), name: "Bitcoin".to_string(), kind: AssetKind::Crypto, precision: 8, status: AssetStatus::Active });
+        let mut accounts_system = AccountSystem::new(storage_system.clone(), Arc::new(assets_system));
+        let account_id = accounts_system.create_account(Account { id: 0, name: "Alice".to_string(), timestamp: SystemTime::now() });
+        Exchange {
+            accounts_system,
+            storage_system,
+            adapter: MockFundingAdapter::new(HashSet::from([btc_id])),
+            account_id,
+            usd_id,
+            btc_id,
+            _database: database,
+        }
+    }
+
+    /// Deposits BTC and waits for its confirmations.
+    fn deposit_btc(&mut self, amount: Amount) {
+        let reference = self.adapter.receive();
+        let deposit = self.accounts_system.create_deposit(self.account_id, self.btc_id, amount, reference, &self.adapter).unwrap();
+        while self.accounts_system.update_deposit(deposit.id, &self.adapter).unwrap().status == FundingStatus::Pending {
+            self.adapter.mine();
+        }
+        self.accounts_system.complete_deposit(deposit.id).unwrap();
+    }
+
+    fn balance_and_held(&self, asset_id: u64) -> (Amount, Amount) {
+        let account_asset = self.storage_system.get_account_asset(self.account_id, asset_id).unwrap();
+        (account_asset.balance, account_asset.held)
+    }
+}
+
+#[test]
+fn crypto_deposit_is_credited_once_confirmed() {
+    let mut exchange = Exchange::new();
+    let reference = exchange.adapter.receive();
+    let deposit = exchange.accounts_system.create_deposit(exchange.account_id, exchange.btc_id, Amount::new(15, 1), reference, &exchange.adapter).unwrap();
+    for confirmations in 0..3 {
+        let deposit = exchange.accounts_system.update_deposit(deposit.id, &exchange.adapter).unwrap();
+        assert_eq!((deposit.status, deposit.confirmations), (FundingStatus::Pending, confirmations));
+        assert_eq!(exchange.accounts_system.complete_deposit(deposit.id).unwrap_err(), FundingError::InvalidStatus { status: FundingStatus::Pending });
+        assert!(exchange.accounts_system.get_available_balance(exchange.account_id, exchange.btc_id).is_zero());
+        exchange.adapter.mine();
+    }
+    assert_eq!(exchange.accounts_system.update_deposit(deposit.id, &exchange.adapter).unwrap().status, FundingStatus::Confirmed);
+    assert_eq!(exchange.accounts_system.complete_deposit(deposit.id).unwrap().status, FundingStatus::Completed);
+    assert_eq!(exchange.balance_and_held(exchange.btc_id), (Amount::new(15, 1), Amount::ZERO));
+    exchange.accounts_system.ledger_system.check_trial_balance().unwrap();
+}
+
+#[test]
+fn failed_bank_deposit_is_rejected() {
+    let mut exchange = Exchange::new();
+    let reference = exchange.adapter.receive();
+    let deposit = exchange.accounts_system.create_deposit(exchange.account_id, exchange.usd_id, Amount::from(1000), reference.clone(), &exchange.adapter).unwrap();
+    exchange.adapter.fail(&reference);
+    assert_eq!(exchange.accounts_system.update_deposit(deposit.id, &exchange.adapter).unwrap().status, FundingStatus::Rejected);
+    assert_eq!(exchange.accounts_system.complete_deposit(deposit.id).unwrap_err(), FundingError::InvalidStatus { status: FundingStatus::Rejected });
+    assert!(exchange.accounts_system.get_available_balance(exchange.account_id, exchange.usd_id).is_zero());
+}
+
+#[test]
+fn unknown_deposit_stays_pending() {
+    let mut exchange = Exchange::new();
+    let deposit = exchange.accounts_system.create_deposit(exchange.account_id, exchange.usd_id, Amount::from(1000), "wire-123".to_string(), &exchange.adapter).unwrap();
+    assert_eq!(exchange.accounts_system.update_deposit(deposit.id, &exchange.adapter).unwrap().status, FundingStatus::Pending);
+    assert_eq!(exchange.accounts_system.reject_deposit(deposit.id).unwrap().status, FundingStatus::Rejected);
+}
+
+#[test]
+fn deposit_reference_is_used_once() {
+    let mut exchange = Exchange::new();
+    let reference = exchange.adapter.receive();
+    let deposit = exchange.accounts_system.create_deposit(exchange.account_id, exchange.usd_id, Amount::from(1000), reference.clone(), &exchange.adapter).unwrap();
+    let error = exchange.accounts_system.create_deposit(exchange.account_id, exchange.usd_id, Amount::from(1000), reference.clone(), &exchange.adapter).unwrap_err();
+    assert_eq!(error, FundingError::DuplicateReference { reference, deposit_id: deposit.id });
+}
+
+#[test]
+fn deposit_amount_is_checked() {
+    let mut exchange = Exchange::new();
+    let reference = exchange.adapter.receive();
+    let error = exchange.accounts_system.create_deposit(exchange.account_id, exchange.usd_id, Amount::new(1001, 3), reference, &exchange.adapter).unwrap_err();
+    assert_eq!(error, FundingError::InvalidAmount { amount: Amount::new(1001, 3), asset_id: exchange.usd_id });
+}
+
+#[test]
+fn withdrawal_is_held_until_confirmed() {
+    let mut exchange = Exchange::new();
+    exchange.deposit_btc(Amount::new(15, 1));
+    let withdrawal = exchange.accounts_system.request_withdrawal(exchange.account_id, exchange.btc_id, Amount::from(1), "bc1qexample".to_string()).unwrap();
+    assert_eq!(exchange.balance_and_held(exchange.btc_id), (Amount::new(15, 1), Amount::from(1)));
+    let error = exchange.accounts_system.request_withdrawal(exchange.account_id, exchange.btc_id, Amount::from(1), "bc1qexample".to_string()).unwrap_err();
+    assert_eq!(error, FundingError::InsufficientFunds { asset_id: exchange.btc_id, required: Amount::from(1), available: Amount::new(5, 1) });
+
+    exchange.accounts_system.approve_withdrawal(withdrawal.id, &mut exchange.adapter).unwrap();
+    while exchange.accounts_system.update_withdrawal(withdrawal.id, &exchange.adapter).unwrap().status == FundingStatus::Confirmed {
+        assert_eq!(exchange.balance_and_held(exchange.btc_id), (Amount::new(15, 1), Amount::from(1)));
+        exchange.adapter.mine();
+    }
+    assert_eq!(exchange.accounts_system.get_withdrawal(withdrawal.id).unwrap().status, FundingStatus::Completed);
+    assert_eq!(exchange.balance_and_held(exchange.btc_id), (Amount::new(5, 1), Amount::ZERO));
+    exchange.accounts_system.ledger_system.check_trial_balance().unwrap();
+}
+
+#[test]
+fn unknown_withdrawal_stays_held_until_failed() {
+    let mut exchange = Exchange::new();
+    exchange.deposit_btc(Amount::from(1));
+    let withdrawal = exchange.accounts_system.request_withdrawal(exchange.account_id, exchange.btc_id, Amount::new(4, 1), "bc1qexample".to_string()).unwrap();
+    let reference = exchange.accounts_system.approve_withdrawal(withdrawal.id, &mut exchange.adapter).unwrap().reference.unwrap();
+    exchange.adapter.forget(&reference);
+    assert_eq!(exchange.accounts_system.update_withdrawal(withdrawal.id, &exchange.adapter).unwrap().status, FundingStatus::Confirmed);
+    assert_eq!(exchange.balance_and_held(exchange.btc_id), (Amount::from(1), Amount::new(4, 1)));
+
+    exchange.adapter.fail(&reference);
+    assert_eq!(exchange.accounts_system.update_withdrawal(withdrawal.id, &exchange.adapter).unwrap().status, FundingStatus::Rejected);
+    assert_eq!(exchange.balance_and_held(exchange.btc_id), (Amount::from(1), Amount::ZERO));
+}
+
+#[test]
+fn rejected_withdrawal_releases_hold() {
+    let mut exchange = Exchange::new();
+    exchange.deposit_btc(Amount::from(1));
+    let withdrawal = exchange.accounts_system.request_withdrawal(exchange.account_id, exchange.btc_id, Amount::new(2, 1), "bc1qexample".to_string()).unwrap();
+    assert_eq!(exchange.accounts_system.reject_withdrawal(withdrawal.id).unwrap().status, FundingStatus::Rejected);
+    assert_eq!(exchange.balance_and_held(exchange.btc_id), (Amount::from(1), Amount::ZERO));
+    assert_eq!(exchange.accounts_system.reject_withdrawal(withdrawal.id).unwrap_err(), FundingError::InvalidStatus { status: FundingStatus::Rejected });
+}
+
+#[test]
+fn withdrawal_stays_pending_if_sending_fails() {
+    let mut exchange = Exchange::new();
+    exchange.deposit_btc(Amount::from(1));
+    let withdrawal = exchange.accounts_system.request_withdrawal(exchange.account_id, exchange.btc_id, Amount::new(2, 1), String::new()).unwrap();
+    let error = exchange.accounts_system.approve_withdrawal(withdrawal.id, &mut exchange.adapter).unwrap_err();
+    assert_eq!(error, FundingError::Adapter("no destination".to_string()));
+    assert_eq!(exchange.accounts_system.get_withdrawal(withdrawal.id).unwrap().status, FundingStatus::Pending);
+    assert_eq!(exchange.balance_and_held(exchange.btc_id), (Amount::from(1), Amount::new(2, 1)));
+}
@@ -0,0 +1,668 @@
+use std::time::{Duration, SystemTime};
+use kubera::amount::Amount;
+use kubera::book::BookSide;
+use kubera::matcher::{MatcherEvent, OrderMatcher, RejectReason, SelfTradePrevention};
+use kubera::orders::{ExecutionType, Order, PriceType, SlippageLimit, TimeInForce, TradeType, TrailingOffset};
+
+const MARKET_ID: u64 = 1;
+
+/// Matcher of a market with 8 decimal places for the base asset and 2 for the quote asset.
+fn new_matcher() -> OrderMatcher {
+    OrderMatcher::new(MARKET_ID, 8, 2)
+}
+
+/// Limit order of an account of its own, so no self-trade prevention gets in the way.
+fn limit(id: u64, trade_type: TradeType, price: i64, quantity: i64) -> Order {
+    Order { id, account_id: id, market_id: MARKET_ID, trade_type, price_type: PriceType::Limit(Amount::from(price)), quantity: Amount::from(quantity), ..Default::default() }
+}
+
+/// All-or-none limit order.
+fn full_limit(id: u64, trade_type: TradeType, price: i64, quantity: i64) -> Order {
+    Order { execution_type: ExecutionType::Full, ..limit(id, trade_type, price, quantity) }
+}
+
+/// Market order, buys hold plenty of the quote asset since they can't spend more than they hold.
+fn market(id: u64, trade_type: TradeType, quantity: i64) -> Order {
+    let held = match trade_type {
+        TradeType::Buy => Amount::from(1_000_000),
+        TradeType::Sell => Amount::from(quantity),
+    };
+    Order { id, account_id: id, market_id: MARKET_ID, trade_type, price_type: PriceType::Market, quantity: Amount::from(quantity), held, ..Default::default() }
+}
+
+/// Two other accounts trade one unit at the given price, orders get ids from `id` on.
+fn trade_at(matcher: &mut OrderMatcher, id: u64, price: i64) -> Vec<MatcherEvent> {
+    matcher.add_order(limit(id, TradeType::Sell, price, 1));
+    matcher.add_order(limit(id + 1, TradeType::Buy, price, 1));
+    matcher.match_orders()
+}
+
+/// Trades as (buy order id, sell order id, quantity, price).
+fn trades(events: &[MatcherEvent]) -> Vec<(u64, u64, Amount, Amount)> {
+    events.iter()
+        .filter_map(|event| match event {
+            MatcherEvent::Match(order_match) => Some((order_match.buy_order_id, order_match.sell_order_id, order_match.quantity, order_match.price)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Orders of one side of the book in priority order, with the quantity they show.
+fn book(side: &BookSide) -> Vec<(u64, Amount)> {
+    side.iter().map(|order| (order.id, order.quantity)).collect()
+}
+
+#[test]
+fn book_sides_are_sorted_by_price_then_arrival() {
+    let mut matcher = new_matcher();
+    for order in [
+        limit(1, TradeType::Buy, 99, 1),
+        limit(2, TradeType::Buy, 101, 1),
+        limit(3, TradeType::Buy, 100, 1),
+        limit(4, TradeType::Buy, 101, 1),
+        limit(5, TradeType::Sell, 103, 1),
+        limit(6, TradeType::Sell, 102, 1),
+        limit(7, TradeType::Sell, 102, 1),
+    ] {
+        matcher.add_order(order);
+    }
+    assert!(trades(&matcher.match_orders()).is_empty());
+    let ids = |side: &BookSide| book(side).into_iter().map(|(id, _)| id).collect::<Vec<u64>>();
+    assert_eq!(ids(&matcher.bids), [2, 4, 3, 1]);
+    assert_eq!(ids(&matcher.asks), [6, 7, 5]);
+    assert_eq!(matcher.bids.best_price(), Some(Amount::from(101)));
+    assert_eq!(matcher.asks.best_price(), Some(Amount::from(102)));
+}
+
+#[test]
+fn taker_fills_best_price_first_then_oldest_order() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 101, 1));
+    matcher.add_order(limit(2, TradeType::Sell, 100, 1));
+    matcher.add_order(limit(3, TradeType::Sell, 100, 1));
+    matcher.match_orders();
+    matcher.add_order(market(4, TradeType::Buy, 2));
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [
+        (4, 2, Amount::from(1), Amount::from(100)),
+        (4, 3, Amount::from(1), Amount::from(100)),
+    ]);
+    assert_eq!(book(&matcher.asks), [(1, Amount::from(1))]);
+    assert!(matcher.bids.is_empty());
+}
+
+#[test]
+fn crossing_limit_orders_trade_at_the_maker_price() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 50000, 1));
+    matcher.add_order(limit(2, TradeType::Buy, 51000, 1));
+    assert_eq!(trades(&matcher.match_orders()), [(2, 1, Amount::from(1), Amount::from(50000))]);
+
+    matcher.add_order(limit(3, TradeType::Buy, 51000, 1));
+    matcher.add_order(limit(4, TradeType::Sell, 50000, 1));
+    assert_eq!(trades(&matcher.match_orders()), [(3, 4, Amount::from(1), Amount::from(51000))]);
+    assert!(matcher.bids.is_empty() && matcher.asks.is_empty());
+}
+
+#[test]
+fn limit_orders_that_do_not_cross_rest() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 101, 1));
+    matcher.add_order(limit(2, TradeType::Buy, 100, 1));
+    assert!(trades(&matcher.match_orders()).is_empty());
+    assert_eq!(book(&matcher.bids), [(2, Amount::from(1))]);
+    assert_eq!(book(&matcher.asks), [(1, Amount::from(1))]);
+}
+
+#[test]
+fn market_sell_fills_resting_buys_and_the_rest_rests() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Buy, 100, 2));
+    matcher.add_order(limit(2, TradeType::Buy, 99, 1));
+    matcher.match_orders();
+    matcher.add_order(market(3, TradeType::Sell, 5));
+    assert_eq!(trades(&matcher.match_orders()), [
+        (1, 3, Amount::from(2), Amount::from(100)),
+        (2, 3, Amount::from(1), Amount::from(99)),
+    ]);
+    assert_eq!(book(&matcher.asks), [(3, Amount::from(2))]);
+
+    // A resting market order trades at the price of the incoming limit order
+    matcher.add_order(limit(4, TradeType::Buy, 98, 1));
+    assert_eq!(trades(&matcher.match_orders()), [(4, 3, Amount::from(1), Amount::from(98))]);
+}
+
+#[test]
+fn all_or_none_order_waits_until_the_book_can_fill_it() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 100, 3));
+    matcher.add_order(full_limit(2, TradeType::Buy, 100, 5));
+    assert!(trades(&matcher.match_orders()).is_empty());
+    assert_eq!(book(&matcher.bids), [(2, Amount::from(5))]);
+
+    matcher.add_order(limit(3, TradeType::Sell, 100, 2));
+    assert_eq!(trades(&matcher.match_orders()), [
+        (2, 1, Amount::from(3), Amount::from(100)),
+        (2, 3, Amount::from(2), Amount::from(100)),
+    ]);
+    assert!(matcher.bids.is_empty() && matcher.asks.is_empty());
+}
+
+#[test]
+fn partial_order_fills_what_it_can_and_rests() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 100, 3));
+    matcher.add_order(limit(2, TradeType::Buy, 100, 5));
+    assert_eq!(trades(&matcher.match_orders()), [(2, 1, Amount::from(3), Amount::from(100))]);
+    assert_eq!(book(&matcher.bids), [(2, Amount::from(2))]);
+}
+
+#[test]
+fn taker_passes_over_all_or_none_orders_it_cannot_fill() {
+    let mut matcher = new_matcher();
+    matcher.add_order(full_limit(1, TradeType::Buy, 101, 5));
+    matcher.add_order(limit(2, TradeType::Buy, 100, 2));
+    matcher.match_orders();
+    matcher.add_order(market(3, TradeType::Sell, 2));
+    assert_eq!(trades(&matcher.match_orders()), [(2, 3, Amount::from(2), Amount::from(100))]);
+    assert_eq!(book(&matcher.bids), [(1, Amount::from(5))]);
+}
+
+#[test]
+fn immediate_or_cancel_cancels_what_is_not_filled() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 100, 3));
+    matcher.add_order(Order { time_in_force: TimeInForce::ImmediateOrCancel, ..limit(2, TradeType::Buy, 100, 5) });
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [(2, 1, Amount::from(3), Amount::from(100))]);
+    assert!(matches!(events.last(), Some(MatcherEvent::Cancelled { order_id: 2, quantity, .. }) if *quantity == Amount::from(2)));
+    assert!(matcher.bids.is_empty());
+}
+
+#[test]
+fn fill_or_kill_is_rejected_unless_filled_completely() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 100, 3));
+    matcher.add_order(limit(2, TradeType::Sell, 101, 3));
+    matcher.add_order(Order { time_in_force: TimeInForce::FillOrKill, ..limit(3, TradeType::Buy, 100, 5) });
+    let events = matcher.match_orders();
+    assert!(trades(&events).is_empty());
+    assert!(matches!(events[..], [MatcherEvent::Rejected { order_id: 3, reason: RejectReason::FillOrKill, .. }]));
+
+    matcher.add_order(Order { time_in_force: TimeInForce::FillOrKill, ..limit(4, TradeType::Buy, 101, 5) });
+    assert_eq!(trades(&matcher.match_orders()), [
+        (4, 1, Amount::from(3), Amount::from(100)),
+        (4, 2, Amount::from(2), Amount::from(101)),
+    ]);
+    assert_eq!(book(&matcher.asks), [(2, Amount::from(1))]);
+}
+
+#[test]
+fn good_till_date_order_expires_at_its_deadline() {
+    let mut matcher = new_matcher();
+    let past = SystemTime::now() - Duration::from_secs(1);
+    matcher.add_order(Order { time_in_force: TimeInForce::GoodTillDate(past), ..limit(1, TradeType::Buy, 100, 1) });
+    assert!(matches!(matcher.match_orders()[..], [MatcherEvent::Expired { order_id: 1, .. }]));
+
+    let deadline = SystemTime::now() + Duration::from_millis(50);
+    matcher.add_order(Order { time_in_force: TimeInForce::GoodTillDate(deadline), ..limit(2, TradeType::Buy, 100, 1) });
+    assert!(matcher.match_orders().is_empty());
+    assert_eq!(book(&matcher.bids), [(2, Amount::from(1))]);
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(matches!(matcher.match_orders()[..], [MatcherEvent::Expired { order_id: 2, quantity, .. }] if quantity == Amount::from(1)));
+    assert!(matcher.bids.is_empty());
+}
+
+#[test]
+fn cancel_takes_the_order_off_the_book() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Buy, 100, 2));
+    matcher.add_order(limit(2, TradeType::Buy, 100, 3));
+    matcher.match_orders();
+    assert!(matches!(matcher.cancel_order(1), MatcherEvent::Cancelled { order_id: 1, quantity, .. } if quantity == Amount::from(2)));
+    assert_eq!(book(&matcher.bids), [(2, Amount::from(3))]);
+    assert!(matches!(matcher.cancel_order(1), MatcherEvent::OrderNotFound { order_id: 1, .. }));
+
+    // Not matched yet
+    matcher.add_order(limit(3, TradeType::Sell, 100, 3));
+    assert!(matches!(matcher.cancel_order(3), MatcherEvent::Cancelled { order_id: 3, .. }));
+    assert!(trades(&matcher.match_orders()).is_empty());
+    assert_eq!(book(&matcher.bids), [(2, Amount::from(3))]);
+}
+
+#[test]
+fn amend_keeps_priority_only_when_reducing_the_quantity() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Buy, 100, 3));
+    matcher.add_order(limit(2, TradeType::Buy, 100, 3));
+    matcher.match_orders();
+
+    let event = matcher.amend_order(1, None, Some(Amount::from(2)));
+    assert!(matches!(event, MatcherEvent::Amended { order_id: 1, priority_kept: true, .. }));
+    assert_eq!(book(&matcher.bids), [(1, Amount::from(2)), (2, Amount::from(3))]);
+
+    let event = matcher.amend_order(1, None, Some(Amount::from(4)));
+    assert!(matches!(event, MatcherEvent::Amended { order_id: 1, priority_kept: false, .. }));
+    matcher.match_orders();
+    assert_eq!(book(&matcher.bids), [(2, Amount::from(3)), (1, Amount::from(4))]);
+}
+
+#[test]
+fn amended_price_is_matched_like_a_new_order() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 101, 1));
+    matcher.add_order(limit(2, TradeType::Buy, 100, 1));
+    matcher.match_orders();
+    let event = matcher.amend_order(2, Some(Amount::from(101)), None);
+    assert!(matches!(event, MatcherEvent::Amended { order_id: 2, price_type: PriceType::Limit(price), priority_kept: false, .. } if price == Amount::from(101)));
+    assert_eq!(trades(&matcher.match_orders()), [(2, 1, Amount::from(1), Amount::from(101))]);
+}
+
+#[test]
+fn amend_is_rejected_for_market_prices_and_unknown_orders() {
+    let mut matcher = new_matcher();
+    matcher.add_order(market(1, TradeType::Buy, 1));
+    matcher.match_orders();
+    assert!(matches!(matcher.amend_order(1, Some(Amount::from(100)), None), MatcherEvent::AmendRejected { order_id: 1, .. }));
+    assert!(matches!(matcher.amend_order(1, None, Some(Amount::ZERO)), MatcherEvent::AmendRejected { order_id: 1, .. }));
+    assert!(matches!(matcher.amend_order(2, None, Some(Amount::from(1))), MatcherEvent::OrderNotFound { order_id: 2, .. }));
+    assert_eq!(book(&matcher.bids), [(1, Amount::from(1))]);
+}
+
+#[test]
+fn stop_market_waits_for_the_stop_price_then_trades_as_market_order() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Buy, 100, 1));
+    matcher.add_order(limit(2, TradeType::Buy, 95, 1));
+    matcher.add_order(Order { price_type: PriceType::StopMarket(Amount::from(100)), ..market(3, TradeType::Sell, 1) });
+    assert!(matcher.match_orders().is_empty());
+    assert_eq!(matcher.stop_orders.len(), 1);
+
+    matcher.add_order(limit(4, TradeType::Sell, 100, 1));
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [
+        (1, 4, Amount::from(1), Amount::from(100)),
+        (2, 3, Amount::from(1), Amount::from(95)),
+    ]);
+    assert!(events.iter().any(|event| matches!(event, MatcherEvent::Triggered { order_id: 3, price_type: PriceType::Market, .. })));
+    assert!(matcher.stop_orders.is_empty());
+}
+
+#[test]
+fn stop_limit_enters_the_book_at_its_limit_price() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 105, 1));
+    matcher.add_order(limit(2, TradeType::Sell, 106, 1));
+    matcher.add_order(limit(3, TradeType::Sell, 107, 1));
+    let stop_limit = PriceType::StopLimit { stop_price: Amount::from(105), limit_price: Amount::from(106) };
+    matcher.add_order(Order { price_type: stop_limit, ..limit(4, TradeType::Buy, 0, 3) });
+    assert!(matcher.match_orders().is_empty());
+
+    matcher.add_order(limit(5, TradeType::Buy, 105, 1));
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [
+        (5, 1, Amount::from(1), Amount::from(105)),
+        (4, 2, Amount::from(1), Amount::from(106)),
+    ]);
+    assert!(events.iter().any(|event| matches!(event, MatcherEvent::Triggered { order_id: 4, price_type: PriceType::Limit(price), .. } if *price == Amount::from(106))));
+    assert_eq!(book(&matcher.bids), [(4, Amount::from(2))]);
+}
+
+/// Stop prices the trailing stop was moved to.
+fn trailing_stop_moves(events: &[MatcherEvent]) -> Vec<Amount> {
+    events.iter()
+        .filter_map(|event| match event {
+            MatcherEvent::TrailingStopMoved { stop_price, .. } => Some(*stop_price),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn trailing_stop_follows_the_best_price_and_triggers_on_the_way_back() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Buy, 50, 1));
+    matcher.add_order(Order { price_type: PriceType::TrailingStop(TrailingOffset::Amount(Amount::from(10))), ..market(2, TradeType::Sell, 1) });
+    matcher.match_orders();
+
+    assert_eq!(trailing_stop_moves(&trade_at(&mut matcher, 10, 100)), [Amount::from(90)]);
+    assert_eq!(trailing_stop_moves(&trade_at(&mut matcher, 20, 110)), [Amount::from(100)]);
+    let events = trade_at(&mut matcher, 30, 105);
+    assert!(trailing_stop_moves(&events).is_empty());
+    assert_eq!(matcher.stop_orders.len(), 1);
+
+    let events = trade_at(&mut matcher, 40, 100);
+    assert!(events.iter().any(|event| matches!(event, MatcherEvent::Triggered { order_id: 2, .. })));
+    assert_eq!(trades(&events)[1], (1, 2, Amount::from(1), Amount::from(50)));
+    assert!(matcher.stop_orders.is_empty());
+}
+
+#[test]
+fn trailing_stop_by_percent_trails_below_for_buys() {
+    let mut matcher = new_matcher();
+    matcher.add_order(Order { price_type: PriceType::TrailingStop(TrailingOffset::Percent(Amount::from(10))), ..market(1, TradeType::Buy, 1) });
+    matcher.match_orders();
+    assert_eq!(trailing_stop_moves(&trade_at(&mut matcher, 10, 100)), [Amount::from(110)]);
+    assert_eq!(trailing_stop_moves(&trade_at(&mut matcher, 20, 90)), [Amount::from(99)]);
+    assert!(trailing_stop_moves(&trade_at(&mut matcher, 30, 95)).is_empty());
+    matcher.add_order(limit(40, TradeType::Sell, 120, 1));
+    let events = trade_at(&mut matcher, 50, 99);
+    assert!(events.iter().any(|event| matches!(event, MatcherEvent::Triggered { order_id: 1, .. })));
+    assert_eq!(trades(&events)[1], (1, 40, Amount::from(1), Amount::from(120)));
+}
+
+/// Iceberg limit order showing `display_quantity` of its quantity at a time.
+fn iceberg(id: u64, trade_type: TradeType, price: i64, quantity: i64, display_quantity: i64) -> Order {
+    Order { display_quantity: Some(Amount::from(display_quantity)), ..limit(id, trade_type, price, quantity) }
+}
+
+#[test]
+fn iceberg_shows_one_slice_and_refills_at_the_back_of_its_level() {
+    let mut matcher = new_matcher();
+    matcher.add_order(iceberg(1, TradeType::Sell, 100, 10, 2));
+    matcher.add_order(limit(2, TradeType::Sell, 100, 3));
+    matcher.match_orders();
+    assert_eq!(book(&matcher.asks), [(1, Amount::from(2)), (2, Amount::from(3))]);
+    assert_eq!(matcher.iceberg_reserves[&1], Amount::from(8));
+
+    matcher.add_order(market(3, TradeType::Buy, 3));
+    assert_eq!(trades(&matcher.match_orders()), [
+        (3, 1, Amount::from(2), Amount::from(100)),
+        (3, 2, Amount::from(1), Amount::from(100)),
+    ]);
+    assert_eq!(book(&matcher.asks), [(2, Amount::from(2)), (1, Amount::from(2))]);
+    assert_eq!(matcher.iceberg_reserves[&1], Amount::from(6));
+}
+
+#[test]
+fn fill_or_kill_counts_the_hidden_reserve() {
+    let mut matcher = new_matcher();
+    matcher.add_order(iceberg(1, TradeType::Sell, 100, 10, 2));
+    matcher.match_orders();
+    matcher.add_order(Order { time_in_force: TimeInForce::FillOrKill, ..limit(2, TradeType::Buy, 100, 5) });
+    assert_eq!(trades(&matcher.match_orders()), [
+        (2, 1, Amount::from(2), Amount::from(100)),
+        (2, 1, Amount::from(2), Amount::from(100)),
+        (2, 1, Amount::from(1), Amount::from(100)),
+    ]);
+    assert_eq!(book(&matcher.asks), [(1, Amount::from(1))]);
+    assert_eq!(matcher.iceberg_reserves[&1], Amount::from(4));
+
+    matcher.add_order(Order { time_in_force: TimeInForce::FillOrKill, ..limit(3, TradeType::Buy, 100, 6) });
+    assert!(matches!(matcher.match_orders()[..], [MatcherEvent::Rejected { order_id: 3, reason: RejectReason::FillOrKill, .. }]));
+}
+
+#[test]
+fn all_or_none_taker_counts_the_hidden_reserve() {
+    let mut matcher = new_matcher();
+    matcher.add_order(iceberg(1, TradeType::Sell, 100, 10, 2));
+    matcher.add_order(full_limit(2, TradeType::Buy, 100, 10));
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events).iter().map(|(_, _, quantity, _)| *quantity).sum::<Amount>(), Amount::from(10));
+    assert!(matcher.bids.is_empty() && matcher.asks.is_empty());
+    assert!(matcher.iceberg_reserves.is_empty());
+}
+
+#[test]
+fn post_only_order_rests_unless_it_would_take_liquidity() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 100, 1));
+    matcher.add_order(Order { post_only: true, ..limit(2, TradeType::Buy, 100, 1) });
+    matcher.add_order(Order { post_only: true, ..limit(3, TradeType::Buy, 99, 1) });
+    matcher.add_order(Order { post_only: true, ..market(4, TradeType::Buy, 1) });
+    let events = matcher.match_orders();
+    assert!(trades(&events).is_empty());
+    assert!(matches!(events[..], [
+        MatcherEvent::Rejected { order_id: 2, reason: RejectReason::PostOnly, .. },
+        MatcherEvent::Rejected { order_id: 4, reason: RejectReason::PostOnly, .. },
+    ]));
+    assert_eq!(book(&matcher.bids), [(3, Amount::from(1))]);
+
+    // Resting post-only orders trade as makers
+    matcher.add_order(market(5, TradeType::Sell, 1));
+    assert_eq!(trades(&matcher.match_orders()), [(3, 5, Amount::from(1), Amount::from(99))]);
+}
+
+/// Take profit at 110 and stop loss at 90 of account 1, ids 1 and 2.
+fn oco_orders(matcher: &mut OrderMatcher) {
+    let take_profit = Order { account_id: 1, oco_group_id: Some(1), ..limit(1, TradeType::Sell, 110, 2) };
+    let stop_loss = Order { account_id: 1, oco_group_id: Some(1), price_type: PriceType::StopMarket(Amount::from(90)), ..market(2, TradeType::Sell, 2) };
+    matcher.add_oco_orders(take_profit, stop_loss);
+    matcher.match_orders();
+}
+
+#[test]
+fn trade_of_one_oco_order_cancels_the_other() {
+    let mut matcher = new_matcher();
+    oco_orders(&mut matcher);
+    matcher.add_order(limit(3, TradeType::Buy, 110, 1));
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [(3, 1, Amount::from(1), Amount::from(110))]);
+    assert!(events.iter().any(|event| matches!(event, MatcherEvent::Cancelled { order_id: 2, .. })));
+    assert!(matcher.stop_orders.is_empty());
+    // The rest of the traded order stays
+    assert_eq!(book(&matcher.asks), [(1, Amount::from(1))]);
+    assert!(matcher.oco_groups.is_empty());
+}
+
+#[test]
+fn triggered_stop_of_an_oco_group_cancels_the_limit_order() {
+    let mut matcher = new_matcher();
+    oco_orders(&mut matcher);
+    matcher.add_order(limit(3, TradeType::Buy, 85, 2));
+    let events = trade_at(&mut matcher, 10, 90);
+    assert_eq!(trades(&events)[1], (3, 2, Amount::from(2), Amount::from(85)));
+    assert!(events.iter().any(|event| matches!(event, MatcherEvent::Cancelled { order_id: 1, .. })));
+    assert!(matcher.asks.is_empty());
+}
+
+/// Own sell of account 1 at 100 and a buy of the same account crossing it.
+fn self_trade(self_trade_prevention: SelfTradePrevention, sell_quantity: i64, buy_quantity: i64) -> (OrderMatcher, Vec<MatcherEvent>) {
+    let mut matcher = new_matcher();
+    matcher.self_trade_prevention = self_trade_prevention;
+    matcher.add_order(Order { account_id: 1, ..limit(1, TradeType::Sell, 100, sell_quantity) });
+    matcher.add_order(limit(2, TradeType::Sell, 100, 5));
+    matcher.match_orders();
+    matcher.add_order(Order { account_id: 1, ..limit(3, TradeType::Buy, 100, buy_quantity) });
+    let events = matcher.match_orders();
+    assert!(matches!(events[0], MatcherEvent::SelfTradePrevented { taker_order_id: 3, maker_order_id: 1, .. }));
+    (matcher, events)
+}
+
+#[test]
+fn cancel_newest_cancels_the_incoming_order() {
+    let (matcher, events) = self_trade(SelfTradePrevention::CancelNewest, 2, 3);
+    assert!(trades(&events).is_empty());
+    assert!(matches!(events[1..], [MatcherEvent::Cancelled { order_id: 3, .. }]));
+    assert_eq!(book(&matcher.asks), [(1, Amount::from(2)), (2, Amount::from(5))]);
+}
+
+#[test]
+fn cancel_oldest_cancels_the_resting_order_and_the_taker_goes_on() {
+    let (matcher, events) = self_trade(SelfTradePrevention::CancelOldest, 2, 3);
+    assert!(matches!(events[1], MatcherEvent::Cancelled { order_id: 1, .. }));
+    assert_eq!(trades(&events), [(3, 2, Amount::from(3), Amount::from(100))]);
+    assert_eq!(book(&matcher.asks), [(2, Amount::from(2))]);
+}
+
+#[test]
+fn cancel_both_cancels_both_orders() {
+    let (matcher, events) = self_trade(SelfTradePrevention::CancelBoth, 2, 3);
+    assert!(matches!(events[1..], [MatcherEvent::Cancelled { order_id: 1, .. }, MatcherEvent::Cancelled { order_id: 3, .. }]));
+    assert_eq!(book(&matcher.asks), [(2, Amount::from(5))]);
+}
+
+#[test]
+fn decrement_and_cancel_reduces_both_and_cancels_the_smaller() {
+    let (matcher, events) = self_trade(SelfTradePrevention::DecrementAndCancel, 5, 3);
+    assert!(matches!(events[1..], [MatcherEvent::Amended { order_id: 1, quantity, .. }, MatcherEvent::Cancelled { order_id: 3, .. }] if quantity == Amount::from(2)));
+    assert_eq!(book(&matcher.asks), [(1, Amount::from(2)), (2, Amount::from(5))]);
+
+    let (matcher, events) = self_trade(SelfTradePrevention::DecrementAndCancel, 2, 3);
+    assert!(matches!(events[1..3], [MatcherEvent::Amended { order_id: 3, quantity, .. }, MatcherEvent::Cancelled { order_id: 1, .. }] if quantity == Amount::from(1)));
+    assert_eq!(trades(&events), [(3, 2, Amount::from(1), Amount::from(100))]);
+    assert_eq!(book(&matcher.asks), [(2, Amount::from(4))]);
+}
+
+/// Asks of 3 at 99 of another account, 5 at 100 of account 100 and 5 at 100 of another account.
+fn book_with_own_order(self_trade_prevention: SelfTradePrevention) -> OrderMatcher {
+    let mut matcher = new_matcher();
+    matcher.self_trade_prevention = self_trade_prevention;
+    matcher.add_order(limit(1, TradeType::Sell, 99, 3));
+    matcher.add_order(Order { account_id: 100, ..limit(2, TradeType::Sell, 100, 5) });
+    matcher.add_order(limit(3, TradeType::Sell, 100, 5));
+    matcher.match_orders();
+    matcher
+}
+
+#[test]
+fn all_or_none_orders_stop_at_an_own_order_that_would_cancel_them() {
+    for self_trade_prevention in [SelfTradePrevention::CancelNewest, SelfTradePrevention::CancelBoth, SelfTradePrevention::DecrementAndCancel] {
+        let mut matcher = book_with_own_order(self_trade_prevention);
+        matcher.add_order(Order { account_id: 100, time_in_force: TimeInForce::FillOrKill, ..limit(4, TradeType::Buy, 100, 6) });
+        assert!(matches!(matcher.match_orders()[..], [MatcherEvent::Rejected { order_id: 4, reason: RejectReason::FillOrKill, .. }]), "{self_trade_prevention:?}");
+
+        matcher.add_order(Order { account_id: 100, ..full_limit(5, TradeType::Buy, 100, 6) });
+        assert!(trades(&matcher.match_orders()).is_empty(), "{self_trade_prevention:?}");
+        assert_eq!(book(&matcher.bids), [(5, Amount::from(6))]);
+        assert_eq!(matcher.asks.len(), 3);
+    }
+}
+
+#[test]
+fn all_or_none_orders_pass_over_own_orders_cancelled_as_oldest() {
+    let mut matcher = book_with_own_order(SelfTradePrevention::CancelOldest);
+    matcher.add_order(Order { account_id: 100, time_in_force: TimeInForce::FillOrKill, ..limit(4, TradeType::Buy, 100, 6) });
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [
+        (4, 1, Amount::from(3), Amount::from(99)),
+        (4, 3, Amount::from(3), Amount::from(100)),
+    ]);
+    assert!(events.iter().any(|event| matches!(event, MatcherEvent::Cancelled { order_id: 2, .. })));
+    assert_eq!(book(&matcher.asks), [(3, Amount::from(2))]);
+}
+
+#[test]
+fn quote_buy_spends_its_budget_and_cancels_what_buys_nothing() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 100, 2));
+    matcher.add_order(limit(2, TradeType::Sell, 110, 10));
+    matcher.match_orders();
+    matcher.add_order(Order { quote_quantity: Some(Amount::from(500)), ..market(3, TradeType::Buy, 0) });
+    let events = matcher.match_orders();
+    // 300 left after the first fill buys 2.72727272 at 110 for 299.99, the last 0.01 pays for nothing
+    assert_eq!(trades(&events), [
+        (3, 1, Amount::from(2), Amount::from(100)),
+        (3, 2, "2.72727272".parse().unwrap(), Amount::from(110)),
+    ]);
+    let amounts: Vec<Amount> = events.iter()
+        .filter_map(|event| match event {
+            MatcherEvent::Match(order_match) => Some(order_match.amount),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(amounts, [Amount::from(200), "299.99".parse().unwrap()]);
+    assert!(matches!(events.last(), Some(MatcherEvent::Cancelled { order_id: 3, .. })));
+    assert_eq!(book(&matcher.asks), [(2, "7.27272728".parse().unwrap())]);
+}
+
+#[test]
+fn quote_buy_rests_nothing_once_the_book_runs_out() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 100, 1));
+    matcher.match_orders();
+    matcher.add_order(Order { quote_quantity: Some(Amount::from(500)), ..market(2, TradeType::Buy, 0) });
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [(2, 1, Amount::from(1), Amount::from(100))]);
+    assert!(matches!(events.last(), Some(MatcherEvent::Cancelled { order_id: 2, .. })));
+    assert!(matcher.bids.is_empty() && matcher.asks.is_empty());
+}
+
+/// Asks of one unit each at 100, 101 and 103.
+fn thin_asks() -> OrderMatcher {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 100, 1));
+    matcher.add_order(limit(2, TradeType::Sell, 101, 1));
+    matcher.add_order(limit(3, TradeType::Sell, 103, 1));
+    matcher.match_orders();
+    matcher
+}
+
+#[test]
+fn market_order_with_worst_price_rests_the_rest_at_that_price() {
+    let mut matcher = thin_asks();
+    matcher.add_order(Order { slippage: Some(SlippageLimit::WorstPrice(Amount::from(101))), ..market(4, TradeType::Buy, 3) });
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [
+        (4, 1, Amount::from(1), Amount::from(100)),
+        (4, 2, Amount::from(1), Amount::from(101)),
+    ]);
+    assert!(matches!(events.last(), Some(MatcherEvent::SlippageProtected { order_id: 4, price, quantity, .. }) if *price == Amount::from(101) && *quantity == Amount::from(1)));
+    assert_eq!(book(&matcher.bids), [(4, Amount::from(1))]);
+    assert_eq!(matcher.bids.best_price(), Some(Amount::from(101)));
+}
+
+#[test]
+fn market_order_with_max_deviation_cancels_the_rest_if_immediate() {
+    let mut matcher = thin_asks();
+    let slippage = Some(SlippageLimit::MaxDeviation(Amount::from(1)));
+    matcher.add_order(Order { slippage, time_in_force: TimeInForce::ImmediateOrCancel, ..market(4, TradeType::Buy, 3) });
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events).len(), 2);
+    assert!(matches!(events[events.len() - 2..], [
+        MatcherEvent::SlippageProtected { order_id: 4, .. },
+        MatcherEvent::Cancelled { order_id: 4, .. },
+    ]));
+    assert!(matcher.bids.is_empty());
+    assert_eq!(book(&matcher.asks), [(3, Amount::from(1))]);
+}
+
+#[test]
+fn max_deviation_of_a_sell_is_taken_below_the_best_bid() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Buy, 100, 1));
+    matcher.add_order(limit(2, TradeType::Buy, 98, 1));
+    matcher.match_orders();
+    matcher.add_order(Order { slippage: Some(SlippageLimit::MaxDeviation(Amount::from(1))), ..market(3, TradeType::Sell, 2) });
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [(1, 3, Amount::from(1), Amount::from(100))]);
+    assert_eq!(book(&matcher.asks), [(3, Amount::from(1))]);
+    assert_eq!(matcher.asks.best_price(), Some(Amount::from(99)));
+}
+
+#[test]
+fn max_deviation_without_a_reference_price_is_rejected() {
+    let mut matcher = new_matcher();
+    matcher.add_order(Order { slippage: Some(SlippageLimit::MaxDeviation(Amount::from(1))), ..market(1, TradeType::Buy, 1) });
+    assert!(matches!(matcher.match_orders()[..], [MatcherEvent::Rejected { order_id: 1, reason: RejectReason::NoReferencePrice, .. }]));
+}
+
+// Buys without a price limit
+
+#[test]
+fn market_buy_spends_no_more_than_its_hold() {
+    let mut matcher = new_matcher();
+    matcher.add_order(limit(1, TradeType::Sell, 100, 1));
+    matcher.add_order(limit(2, TradeType::Sell, 101, 1));
+    matcher.add_order(Order { held: Amount::new(1505, 1), ..market(3, TradeType::Buy, 2) });
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [
+        (3, 1, Amount::from(1), Amount::from(100)),
+        (3, 2, Amount::new(5, 1), Amount::from(101)),
+    ]);
+    assert!(matches!(events.last(), Some(MatcherEvent::Cancelled { order_id: 3, quantity, .. }) if *quantity == Amount::new(5, 1)));
+    assert_eq!(book(&matcher.asks), [(2, Amount::new(5, 1))]);
+}
+
+#[test]
+fn resting_market_buy_is_cancelled_once_its_hold_is_spent() {
+    let mut matcher = new_matcher();
+    matcher.add_order(Order { held: Amount::from(150), ..market(1, TradeType::Buy, 2) });
+    matcher.match_orders();
+    matcher.add_order(limit(2, TradeType::Sell, 100, 2));
+    let events = matcher.match_orders();
+    assert_eq!(trades(&events), [(1, 2, Amount::new(15, 1), Amount::from(100))]);
+    assert!(matches!(events.last(), Some(MatcherEvent::Cancelled { order_id: 1, quantity, .. }) if *quantity == Amount::new(5, 1)));
+    assert!(matcher.bids.is_empty());
+    assert_eq!(book(&matcher.asks), [(2, Amount::new(5, 1))]);
+}
@@ -0,0 +1,233 @@
+mod common;
+
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::SystemTime;
+use bincode::{config, decode_from_slice, Decode, Encode};
+use redb::{Database, TableDefinition, TypeName, Value};
+use kubera::amount::Amount;
+use kubera::assets::{AssetKind, MarketStatus};
+use kubera::ledger::LedgerSystem;
+use kubera::orders::{ExecutionType, OrderHistoryType, OrderStatus, PriceType, TradeType};
+use kubera::storage::{MigrationError, StorageSystem};
+use common::TempDatabase;
+
+// Rows as the first version of the crate stored them
+
+#[derive(Debug, Encode, Decode)]
+struct Currency {
+    id: u64,
+    symbol: String,
+}
+
+#[derive(Debug, Encode, Decode)]
+struct CryptoCurrency {
+    id: u64,
+    symbol: String,
+}
+
+#[derive(Debug, Encode, Decode)]
+struct AccountCurrency {
+    id: u64,
+    account_id: u64,
+    currency_id: u64,
+    balance: f64,
+}
+
+#[derive(Debug, Encode, Decode)]
+struct AccountCryptoCurrency {
+    id: u64,
+    account_id: u64,
+    crypto_currency_id: u64,
+    quantity: f64,
+}
+
+#[derive(Debug, Encode, Decode)]
+enum LegacyPriceType {
+    Market,
+    Limit(f64),
+}
+
+#[derive(Debug, Encode, Decode)]
+struct Order {
+    id: u64,
+    account_id: u64,
+    trade_type: TradeType,
+    price_type: LegacyPriceType,
+    execution_type: ExecutionType,
+    crypto_currency_id: u64,
+    currency_id: u64,
+    quantity: f64,
+    timestamp: SystemTime,
+    status: OrderStatus,
+}
+
+#[derive(Debug, Encode, Decode)]
+struct OrderHistory {
+    id: u64,
+    order_id: u64,
+    quantity: f64,
+    timestamp: SystemTime,
+    status: OrderStatus,
+}
+
+/// Type names the rows were stored under.
+trait StoredAs {
+    const TYPE_NAME: &'static str;
+}
+
+#[derive(Debug)]
+struct Row<T>(PhantomData<T>);
+
+impl<T> Value for Row<T>
+    where
+        T: Debug + Encode + Decode<()> + StoredAs,
+{
+    type SelfType<'a> = T where Self: 'a;
+    type AsBytes<'a> = Vec<u8> where Self: 'a;
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> T where Self: 'a {
+        decode_from_slice(data, config::standard()).unwrap().0
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a T) -> Vec<u8> where Self: 'a, Self: 'b {
+        bincode::encode_to_vec(value, config::standard()).unwrap()
+    }
+
+    fn type_name() -> TypeName {
+        TypeName::new(&format!("Bincode<{}>", T::TYPE_NAME))
+    }
+}
+
+impl StoredAs for Currency {
+    const TYPE_NAME: &'static str = "kubera::assets::Currency";
+}
+
+impl StoredAs for CryptoCurrency {
+    const TYPE_NAME: &'static str = "kubera::assets::CryptoCurrency";
+}
+
+impl StoredAs for AccountCurrency {
+    const TYPE_NAME: &'static str = "kubera::accounts::AccountCurrency";
+}
+
+impl StoredAs for AccountCryptoCurrency {
+    const TYPE_NAME: &'static str = "kubera::accounts::AccountCryptoCurrency";
+}
+
+impl StoredAs for Order {
+    const TYPE_NAME: &'static str = "kubera::orders::Order";
+}
+
+impl StoredAs for OrderHistory {
+    const TYPE_NAME: &'static str = "kubera::orders::OrderHistory";
+}
+
+/// Writes the rows into the tables of a database without a schema version.
+struct LegacyDatabase {
+    database: Database,
+}
+
+impl LegacyDatabase {
+    fn create(database: &TempDatabase) -> LegacyDatabase {
+        std::fs::create_dir_all(&database.path).unwrap();
+        LegacyDatabase { database: Database::create(database.path.join("accounts.redb")).unwrap() }
+    }
+
+    fn insert<T>(&self, table_name: &str, rows: &[T])
+        where
+            T: Debug + Encode + Decode<()> + StoredAs + 'static,
+    {
+        let write_txn = self.database.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_table(TableDefinition::<u64, Row<T>>::new(table_name)).unwrap();
+            for (id, row) in rows.iter().enumerate() {
+                table.insert(id as u64 + 1, row).unwrap();
+            }
+        }
+        write_txn.commit().unwrap();
+    }
+
+    /// USD, BTC and ETH, and an account holding USD and BTC.
+    fn with_currencies(self) -> LegacyDatabase {
+        self.insert("currencies", &[Currency { id: 1, symbol: "USD".to_string() }]);
+        self.insert("crypto_currencies", &[
+            CryptoCurrency { id: 1, symbol: "BTC".to_string() },
+            CryptoCurrency { id: 2, symbol: "ETH".to_string() },
+        ]);
+        self.insert("account_currencies", &[AccountCurrency { id: 1, account_id: 1, currency_id: 1, balance: 1000.5 }]);
+        self.insert("account_crypto_currencies", &[AccountCryptoCurrency { id: 1, account_id: 1, crypto_currency_id: 1, quantity: 0.5 }]);
+        self
+    }
+}
+
+fn legacy_order(id: u64, trade_type: TradeType, price_type: LegacyPriceType, crypto_currency_id: u64, quantity: f64, status: OrderStatus) -> Order {
+    Order { id, account_id: 1, trade_type, price_type, execution_type: ExecutionType::Partial, crypto_currency_id, currency_id: 1, quantity, timestamp: SystemTime::now(), status }
+}
+
+#[test]
+fn legacy_orders_are_converted_into_markets() {
+    let database = TempDatabase::new();
+    let legacy = LegacyDatabase::create(&database).with_currencies();
+    legacy.insert("orders", &[
+        legacy_order(1, TradeType::Buy, LegacyPriceType::Limit(50000.25), 1, 0.1, OrderStatus::Open),
+        legacy_order(2, TradeType::Sell, LegacyPriceType::Market, 2, 2.0, OrderStatus::Closed),
+        legacy_order(3, TradeType::Sell, LegacyPriceType::Limit(51000.0), 1, 0.2, OrderStatus::PartiallyFilled),
+    ]);
+    legacy.insert("order_histories", &[OrderHistory { id: 1, order_id: 3, quantity: 0.05, timestamp: SystemTime::now(), status: OrderStatus::PartiallyFilled }]);
+    drop(legacy);
+
+    let storage_system = Arc::new(StorageSystem::open(&database.path).unwrap());
+    let assets = storage_system.load_assets();
+    let symbols: Vec<(&str, AssetKind, u8)> = assets.iter().map(|asset| (asset.symbol.as_str(), asset.kind, asset.precision)).collect();
+    assert_eq!(symbols, [("USD", AssetKind::Fiat, 2), ("BTC", AssetKind::Crypto, 8), ("ETH", AssetKind::Crypto, 8)]);
+    let (usd_id, btc_id, eth_id) = (assets[0].id, assets[1].id, assets[2].id);
+
+    // One market per pair orders were placed for
+    let markets: Vec<(u64, u64, MarketStatus)> = storage_system.load_markets().iter().map(|market| (market.base_asset_id, market.quote_asset_id, market.status)).collect();
+    assert_eq!(markets, [(btc_id, usd_id, MarketStatus::Trading), (eth_id, usd_id, MarketStatus::Trading)]);
+
+    let order = storage_system.get_order(1).unwrap();
+    assert_eq!((order.base_asset_id, order.quote_asset_id, order.trade_type, order.status), (btc_id, usd_id, TradeType::Buy, OrderStatus::Open));
+    assert_eq!((order.price_type, order.quantity, order.held), (PriceType::Limit("50000.25".parse().unwrap()), Amount::new(1, 1), Amount::ZERO));
+    assert_eq!(storage_system.get_order(3).unwrap().market_id, order.market_id);
+    let order = storage_system.get_order(2).unwrap();
+    assert_eq!((order.base_asset_id, order.price_type, order.quantity, order.status), (eth_id, PriceType::Market, Amount::from(2), OrderStatus::Closed));
+    let fills: Vec<(OrderHistoryType, Amount)> = storage_system.get_order_histories_by_order_id(3).iter().map(|order_history| (order_history.history_type, order_history.quantity)).collect();
+    assert_eq!(fills, [(OrderHistoryType::Fill, Amount::new(5, 2))]);
+
+    assert_eq!(storage_system.get_account_asset(1, usd_id).unwrap().balance, Amount::new(10005, 1));
+    assert_eq!(storage_system.get_account_asset(1, btc_id).unwrap().balance, Amount::new(5, 1));
+    LedgerSystem::new(storage_system.clone()).check_trial_balance().unwrap();
+}
+
+#[test]
+fn database_that_cant_be_migrated_is_left_as_it_was() {
+    let database = TempDatabase::new();
+    let legacy = LegacyDatabase::create(&database).with_currencies();
+    legacy.insert("orders", &[legacy_order(1, TradeType::Buy, LegacyPriceType::Market, 9, 0.1, OrderStatus::Open)]);
+    drop(legacy);
+
+    let error = MigrationError::CryptoCurrencyNotFound { table: "orders".to_string(), id: 1, crypto_currency_id: 9 };
+    assert_eq!(StorageSystem::open(&database.path).err(), Some(error.clone()));
+    // Nothing was migrated, the next attempt fails the same way
+    assert_eq!(StorageSystem::open(&database.path).err(), Some(error));
+}
+
+#[test]
+fn balance_that_is_not_a_number_is_an_error() {
+    let database = TempDatabase::new();
+    let legacy = LegacyDatabase::create(&database).with_currencies();
+    legacy.insert("account_currencies", &[AccountCurrency { id: 1, account_id: 1, currency_id: 1, balance: f64::NAN }]);
+    drop(legacy);
+
+    let Err(MigrationError::InvalidAmount { table, id, value }) = StorageSystem::open(&database.path) else {
+        panic!("a NaN balance was migrated");
+    };
+    assert_eq!((table.as_str(), id, value.is_nan()), ("account_currencies", 1, true));
+}
@@ -0,0 +1,319 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+use kubera::accounts::{Account, AccountSystem};
+use kubera::amount::Amount;
+use kubera::assets::{Asset, AssetKind, AssetStatus, AssetSystem, Market, MarketStatus, TradingRules};
+use kubera::fees::FeeSchedule;
+use kubera::matcher::{MatcherEvent, OrderMatcher};
+use kubera::orders::{Order, OrderError, OrderStatus, OrderSystem, PriceType, SlippageLimit, TradeType, TrailingOffset};
+use kubera::storage::StorageSystem;
+use common::TempDatabase;
+
+/// A BTC/USD market and its matcher. The buyer has 1000 USD, the seller 10 BTC
+/// and another account 1000 USD and 10 BTC to trade with.
+struct Exchange {
+    accounts_system: AccountSystem,
+    order_system: OrderSystem,
+    storage_system: Arc<StorageSystem>,
+    matcher: OrderMatcher,
+    market_id: u64,
+    usd_id: u64,
+    btc_id: u64,
+    buyer_id: u64,
+    seller_id: u64,
+    other_id: u64,
+    // Dropped last, once the database is closed
+    _database: TempDatabase,
+}
+
+impl Exchange {
+    fn new() -> Exchange {
+        let database = TempDatabase::new();
+        let storage_system = Arc::new(StorageSystem::open(&database.path).unwrap());
+        let mut assets_system = AssetSystem::new(storage_system.clone());
+        let usd_id = assets_system.create_asset(Asset { id: 0, symbol: "USD".to_string(), name: "US Dollar".to_string(), kind: AssetKind::Fiat, precision: 2, status: AssetStatus::Active });
+        let btc_id = assets_system.create_asset(Asset { id: 0, symbol: "BTC".to_string(), name: "Bitcoin".to_string(), kind: AssetKind::Crypto, precision: 8, status: AssetStatus::Active });
+        let market_id = assets_system.create_market(Market { id: 0, base_asset_id: btc_id, quote_asset_id: usd_id, status: MarketStatus::Trading, trading_rules: TradingRules::default() });
+        let assets_system = Arc::new(assets_system);
+        let mut accounts_system = AccountSystem::new(storage_system.clone(), assets_system.clone());
+        let mut create_account = |name: &str, usd: i64, btc: i64| {
+            let account_id = accounts_system.create_account(Account { id: 0, name: name.to_string(), timestamp: SystemTime::now() });
+            accounts_system.add_asset_to_account(account_id, usd_id, Amount::from(usd));
+            accounts_system.add_asset_to_account(account_id, btc_id, Amount::from(btc));
+            account_id
+        };
+        let buyer_id = create_account("Buyer", 1000, 0);
+        let seller_id = create_account("Seller", 0, 10);
+        let other_id = create_account("Other", 1000, 10);
+        Exchange {
+            accounts_system,
+            order_system: OrderSystem::new(storage_system.clone(), assets_system),
+            storage_system,
+            matcher: OrderMatcher::new(market_id, 8, 2),
+            market_id,
+            usd_id,
+            btc_id,
+            buyer_id,
+            seller_id,
+            other_id,
+            _database: database,
+        }
+    }
+
+    fn order(&self, account_id: u64, trade_type: TradeType, price_type: PriceType, quantity: i64) -> Order {
+        Order { account_id, market_id: self.market_id, trade_type, price_type, quantity: Amount::from(quantity), ..Default::default() }
+    }
+
+    fn limit(&self, account_id: u64, trade_type: TradeType, price: i64, quantity: i64) -> Order {
+        self.order(account_id, trade_type, PriceType::Limit(Amount::from(price)), quantity)
+    }
+
+    /// Creates the order and hands it to the matcher.
+    fn place(&mut self, order: Order) -> Result<Order, OrderError> {
+        let order = self.order_system.create_order(order, &mut self.accounts_system)?;
+        self.matcher.add_order(order);
+        Ok(order)
+    }
+
+    /// Matches and stores everything the matcher reports, the ledger has to balance afterwards.
+    fn run(&mut self) -> Vec<MatcherEvent> {
+        let events = self.matcher.match_orders();
+        for event in events.iter() {
+            self.order_system.process_event(event, &mut self.accounts_system);
+        }
+        self.accounts_system.ledger_system.check_trial_balance().unwrap();
+        events
+    }
+
+    /// Accepts the amendment and stores what the matcher made of it.
+    fn amend(&mut self, order_id: u64, price: Option<i64>, quantity: Option<i64>) -> Result<Order, OrderError> {
+        let (price, quantity) = (price.map(Amount::from), quantity.map(Amount::from));
+        let order = self.order_system.amend_order(order_id, price, quantity, &mut self.accounts_system)?;
+        let event = self.matcher.amend_order(order_id, price, quantity);
+        self.order_system.process_event(&event, &mut self.accounts_system);
+        self.run();
+        Ok(order)
+    }
+
+    /// Two other orders of `other_id` trade one unit at the given price.
+    fn trade_at(&mut self, price: i64) {
+        self.place(self.limit(self.other_id, TradeType::Sell, price, 1)).unwrap();
+        self.place(self.limit(self.other_id, TradeType::Buy, price, 1)).unwrap();
+        self.run();
+    }
+
+    fn balance_and_held(&self, account_id: u64, asset_id: u64) -> (Amount, Amount) {
+        let account_asset = self.storage_system.get_account_asset(account_id, asset_id).unwrap();
+        (account_asset.balance, account_asset.held)
+    }
+
+    fn stored_order(&self, order_id: u64) -> Order {
+        self.storage_system.get_order(order_id).unwrap()
+    }
+}
+
+#[test]
+fn market_buy_holds_the_available_balance_and_releases_what_is_left() {
+    let mut exchange = Exchange::new();
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 100, 1)).unwrap();
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 150, 1)).unwrap();
+    exchange.run();
+
+    let order = exchange.place(exchange.order(exchange.buyer_id, TradeType::Buy, PriceType::Market, 2)).unwrap();
+    assert_eq!(order.held, Amount::from(1000));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(1000), Amount::from(1000)));
+    exchange.run();
+    assert_eq!(exchange.stored_order(order.id).status, OrderStatus::Closed);
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(750), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.btc_id), (Amount::from(2), Amount::ZERO));
+}
+
+#[test]
+fn market_buy_stops_once_its_hold_is_spent() {
+    let mut exchange = Exchange::new();
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 400, 2)).unwrap();
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 500, 1)).unwrap();
+    exchange.run();
+
+    let order = exchange.place(exchange.order(exchange.buyer_id, TradeType::Buy, PriceType::Market, 3)).unwrap();
+    let events = exchange.run();
+    assert!(events.iter().any(|event| matches!(event, MatcherEvent::Cancelled { order_id, quantity, .. } if *order_id == order.id && *quantity == Amount::new(6, 1))));
+    assert_eq!(exchange.stored_order(order.id).status, OrderStatus::Cancelled);
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::ZERO, Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.btc_id), (Amount::new(24, 1), Amount::ZERO));
+
+    // Nothing left to hold
+    let error = exchange.place(exchange.order(exchange.buyer_id, TradeType::Buy, PriceType::Market, 1)).unwrap_err();
+    assert_eq!(error, OrderError::InsufficientFunds { asset_id: exchange.usd_id, required: Amount::new(1, 2), available: Amount::ZERO });
+}
+
+#[test]
+fn market_buy_with_max_deviation_trims_its_hold_to_the_protection_price() {
+    let mut exchange = Exchange::new();
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 100, 1)).unwrap();
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 102, 1)).unwrap();
+    exchange.run();
+
+    let slippage = Some(SlippageLimit::MaxDeviation(Amount::from(1)));
+    let order = exchange.place(Order { slippage, ..exchange.order(exchange.buyer_id, TradeType::Buy, PriceType::Market, 2) }).unwrap();
+    assert_eq!(order.held, Amount::from(1000));
+    exchange.run();
+    // Filled one at 100, the other one rests at 101
+    let stored = exchange.stored_order(order.id);
+    assert_eq!((stored.status, stored.price_type, stored.held), (OrderStatus::PartiallyFilled, PriceType::Limit(Amount::from(101)), Amount::from(101)));
+    assert_eq!(exchange.matcher.bids.get(order.id).unwrap().held, Amount::from(101));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(900), Amount::from(101)));
+}
+
+#[test]
+fn buy_stop_market_holds_the_available_balance_until_triggered() {
+    let mut exchange = Exchange::new();
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 160, 1)).unwrap();
+    exchange.place(exchange.limit(exchange.buyer_id, TradeType::Buy, 150, 1)).unwrap();
+    let order = exchange.place(exchange.order(exchange.buyer_id, TradeType::Buy, PriceType::StopMarket(Amount::from(150)), 1)).unwrap();
+    assert_eq!(order.held, Amount::from(850));
+    exchange.run();
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(1000), Amount::from(1000)));
+
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 150, 1)).unwrap();
+    exchange.run();
+    assert_eq!(exchange.stored_order(order.id).status, OrderStatus::Closed);
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(690), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.btc_id), (Amount::from(2), Amount::ZERO));
+}
+
+#[test]
+fn buy_trailing_stop_holds_the_available_balance_until_triggered() {
+    let mut exchange = Exchange::new();
+    let price_type = PriceType::TrailingStop(TrailingOffset::Amount(Amount::from(100)));
+    let order = exchange.place(exchange.order(exchange.buyer_id, TradeType::Buy, price_type, 1)).unwrap();
+    assert_eq!(order.held, Amount::from(1000));
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 200, 1)).unwrap();
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 210, 1)).unwrap();
+    exchange.run();
+
+    // The stop trails at 200 after a trade at 100 and triggers at the next one
+    exchange.trade_at(100);
+    assert_eq!(exchange.order_system.get_stop_price(order.id), Some(Amount::from(200)));
+    exchange.place(exchange.limit(exchange.other_id, TradeType::Buy, 200, 1)).unwrap();
+    exchange.run();
+    assert_eq!(exchange.stored_order(order.id).status, OrderStatus::Closed);
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(790), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.btc_id), (Amount::from(1), Amount::ZERO));
+}
+
+#[test]
+fn match_moves_both_legs_and_releases_the_holds() {
+    let mut exchange = Exchange::new();
+    let sell = exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 100, 2)).unwrap();
+    let buy = exchange.place(exchange.limit(exchange.buyer_id, TradeType::Buy, 100, 3)).unwrap();
+    exchange.run();
+    assert_eq!(exchange.stored_order(sell.id).status, OrderStatus::Closed);
+    assert_eq!(exchange.stored_order(buy.id).status, OrderStatus::PartiallyFilled);
+    assert_eq!(exchange.balance_and_held(exchange.seller_id, exchange.btc_id), (Amount::from(8), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.seller_id, exchange.usd_id), (Amount::from(200), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.btc_id), (Amount::from(2), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(800), Amount::from(100)));
+
+    let event = exchange.matcher.cancel_order(buy.id);
+    exchange.order_system.process_event(&event, &mut exchange.accounts_system);
+    assert_eq!(exchange.stored_order(buy.id).status, OrderStatus::Cancelled);
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(800), Amount::ZERO));
+    exchange.accounts_system.ledger_system.check_trial_balance().unwrap();
+}
+
+#[test]
+fn oco_orders_share_one_hold_until_one_of_them_trades() {
+    let mut exchange = Exchange::new();
+    exchange.place(exchange.limit(exchange.buyer_id, TradeType::Buy, 80, 1)).unwrap();
+    let take_profit = exchange.limit(exchange.seller_id, TradeType::Sell, 120, 1);
+    let stop_loss = exchange.order(exchange.seller_id, TradeType::Sell, PriceType::StopMarket(Amount::from(90)), 1);
+    let (take_profit, stop_loss) = exchange.order_system.create_oco_orders(take_profit, stop_loss, &mut exchange.accounts_system).unwrap();
+    exchange.matcher.add_oco_orders(take_profit, stop_loss);
+    exchange.run();
+    assert_eq!((take_profit.held, stop_loss.held), (Amount::from(1), Amount::from(1)));
+    assert_eq!(exchange.balance_and_held(exchange.seller_id, exchange.btc_id), (Amount::from(10), Amount::from(1)));
+
+    // The stop loss sells to the bid at 80 once the price falls to 85, the take profit goes
+    exchange.trade_at(85);
+    assert_eq!(exchange.stored_order(stop_loss.id).status, OrderStatus::Closed);
+    assert_eq!(exchange.stored_order(take_profit.id).status, OrderStatus::Cancelled);
+    assert_eq!(exchange.balance_and_held(exchange.seller_id, exchange.btc_id), (Amount::from(9), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.seller_id, exchange.usd_id), (Amount::from(80), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(920), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.btc_id), (Amount::from(1), Amount::ZERO));
+}
+
+#[test]
+fn amendments_hold_more_or_release_funds() {
+    let mut exchange = Exchange::new();
+    let order = exchange.place(exchange.limit(exchange.buyer_id, TradeType::Buy, 100, 2)).unwrap();
+    exchange.run();
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(1000), Amount::from(200)));
+
+    // A higher price holds more right away
+    assert_eq!(exchange.amend(order.id, Some(110), None).unwrap().held, Amount::from(220));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(1000), Amount::from(220)));
+    // A smaller quantity releases funds once the matcher applied it
+    exchange.amend(order.id, None, Some(1)).unwrap();
+    assert_eq!(exchange.stored_order(order.id).held, Amount::from(110));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(1000), Amount::from(110)));
+    let error = exchange.amend(order.id, None, Some(10)).unwrap_err();
+    assert_eq!(error, OrderError::InsufficientFunds { asset_id: exchange.usd_id, required: Amount::from(990), available: Amount::from(890) });
+
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 110, 1)).unwrap();
+    exchange.run();
+    assert_eq!(exchange.stored_order(order.id).status, OrderStatus::Closed);
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(890), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.btc_id), (Amount::from(1), Amount::ZERO));
+}
+
+#[test]
+fn quote_buy_spends_its_budget_and_releases_what_is_left() {
+    let mut exchange = Exchange::new();
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 100, 1)).unwrap();
+    exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 150, 1)).unwrap();
+    exchange.run();
+
+    let order = exchange.place(Order { quote_quantity: Some(Amount::from(200)), ..exchange.order(exchange.buyer_id, TradeType::Buy, PriceType::Market, 0) }).unwrap();
+    assert_eq!(order.held, Amount::from(200));
+    exchange.run();
+    // 100 for the first one, 99.99 for two thirds of the second, the cent left buys nothing
+    assert_eq!(exchange.stored_order(order.id).status, OrderStatus::Cancelled);
+    let bought: Amount = "1.66666666".parse().unwrap();
+    let paid: Amount = "199.99".parse().unwrap();
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.btc_id), (bought, Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.usd_id), (Amount::from(1000) - paid, Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.seller_id, exchange.usd_id), (paid, Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.seller_id, exchange.btc_id), (Amount::from(10) - bought, Amount::from(2) - bought));
+}
+
+#[test]
+fn maker_rebates_are_paid_only_from_what_the_fee_account_holds() {
+    let mut exchange = Exchange::new();
+    let fee_account_id = exchange.accounts_system.create_account(Account { id: 0, name: "Fees".to_string(), timestamp: SystemTime::now() });
+    let fee_schedule = FeeSchedule { market_id: exchange.market_id, maker_rate: "-0.001".parse().unwrap(), taker_rate: "0.002".parse().unwrap(), fee_account_id, tiers: vec![] };
+    exchange.accounts_system.fee_system.set_fee_schedule(&fee_schedule);
+
+    // The taker fee is collected in BTC, there is no USD to pay the rebate of the seller with
+    let sell = exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 100, 1)).unwrap();
+    exchange.place(exchange.limit(exchange.buyer_id, TradeType::Buy, 100, 1)).unwrap();
+    exchange.run();
+    assert_eq!(exchange.balance_and_held(fee_account_id, exchange.btc_id), (Amount::new(2, 3), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(fee_account_id, exchange.usd_id), (Amount::ZERO, Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.buyer_id, exchange.btc_id), (Amount::new(998, 3), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.seller_id, exchange.usd_id), (Amount::from(100), Amount::ZERO));
+    assert!(exchange.accounts_system.fee_system.get_fees_by_order_id(sell.id).is_empty());
+
+    // Paid in full once the fee account holds USD
+    exchange.accounts_system.add_asset_to_account(fee_account_id, exchange.usd_id, Amount::from(1));
+    let sell = exchange.place(exchange.limit(exchange.seller_id, TradeType::Sell, 100, 1)).unwrap();
+    exchange.place(exchange.limit(exchange.buyer_id, TradeType::Buy, 100, 1)).unwrap();
+    exchange.run();
+    let rebates: Vec<Amount> = exchange.accounts_system.fee_system.get_fees_by_order_id(sell.id).iter().map(|fee| fee.amount).collect();
+    assert_eq!(rebates, [Amount::new(-10, 2)]);
+    assert_eq!(exchange.balance_and_held(fee_account_id, exchange.usd_id), (Amount::new(90, 2), Amount::ZERO));
+    assert_eq!(exchange.balance_and_held(exchange.seller_id, exchange.usd_id), (Amount::new(20010, 2), Amount::ZERO));
+}